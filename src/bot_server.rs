@@ -1,27 +1,122 @@
+mod admin;
+mod api;
 pub(crate) mod feishu_client;
+mod health;
+mod login;
+pub(crate) mod mail_url;
+mod mail_view;
+
+pub use login::WebLogin;
+pub use mail_url::MailUrlGen;
 
 use crate::bot_dto::{
-    AddOrRemoveBot, Challenge, ChatType, Event, EventRequest, EventV2, ReceivedMessage,
+    AddOrRemoveBot, Challenge, ChatType, Event, EventMessage, EventRequest, EventV2,
+    ReceivedMessage,
 };
 use crate::bot_server::feishu_client::Client;
+use crate::bot_server::mail_url::LinkQuery;
+use crate::proxy_protocol::{ProxyProtocol, HEADER_TIMEOUT};
+use crate::shutdown::Shutdown;
+use crate::smtp_server::auth::issue_credential;
+use crate::smtp_server::mail::{parse_mail, ParsedMail};
 use crate::store::Store;
+use crate::{config, listen, tls};
+use actix_http::error::DispatchError;
+use actix_http::{HttpService, Protocol};
+use actix_service::{fn_service, map_config, ServiceFactoryExt};
+use actix_web::dev::{AppConfig, ServerHandle};
+use actix_web::error::{ErrorForbidden, ErrorInternalServerError, ErrorNotFound, InternalError};
+use actix_web::http::StatusCode;
+use actix_web::rt;
+use actix_web::rt::net::TcpStream;
 use actix_web::web::Data;
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
-use anyhow::Result;
-use log::{debug, info};
-use serde::{Deserialize, Serialize};
-use std::fmt::Display;
-use std::time::UNIX_EPOCH;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use rustls::ServerConfig;
+use serde::Deserialize;
+use std::io;
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+
+/// Runs store calls and mail parsing off the async workers. Handlers pass a cloned
+/// store since the connection can't be shared between threads.
+pub(crate) async fn blocking<T, E, F>(f: F) -> actix_web::Result<T>
+where
+    F: FnOnce() -> Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: Into<actix_web::Error> + Send + 'static,
+{
+    web::block(f)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .map_err(Into::into)
+}
+
+/// A plain text error from the blocking pool, `actix_web::Error` can't be sent
+/// across threads.
+#[derive(Debug)]
+pub(crate) struct WebError(pub StatusCode, pub String);
+
+impl WebError {
+    pub fn not_found(what: &str) -> Self {
+        WebError(StatusCode::NOT_FOUND, format!("{} not found", what))
+    }
+}
+
+impl From<anyhow::Error> for WebError {
+    fn from(e: anyhow::Error) -> Self {
+        WebError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+impl From<WebError> for actix_web::Error {
+    fn from(e: WebError) -> Self {
+        InternalError::new(e.1, e.0).into()
+    }
+}
+
+/// The verification token of the Feishu app, events carrying another token
+/// are forged and dropped.
+pub struct VerificationToken(pub String);
+
+impl VerificationToken {
+    fn matches(&self, token: &str) -> bool {
+        // compare without an early exit so the token can't be guessed byte by byte
+        let expected = self.0.as_bytes();
+        expected.len() == token.len()
+            && expected
+                .iter()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
 
 async fn event(
     req: web::Json<EventRequest>,
     store: web::Data<Store>,
     client: web::Data<Client>,
+    verification: web::Data<VerificationToken>,
 ) -> HttpResponse {
-    info!("event: {:?}", &req);
+    // the payload carries the verification token and message texts, only log ids
     let (event, event_type) = match &*req {
-        EventRequest::Challenge(c) => return HttpResponse::Ok().json(c),
-        EventRequest::EventV2(EventV2 { event, header, .. }) => (event, &header.event_type),
+        EventRequest::Challenge(c) => {
+            info!("event: url verification");
+            return HttpResponse::Ok().json(c);
+        }
+        EventRequest::EventV2(EventV2 { event, header, .. }) => {
+            if !verification.matches(&header.token) {
+                warn!("event {} with invalid token, dropped", header.event_id);
+                return HttpResponse::Forbidden().json("invalid token");
+            }
+            info!("event: {}, id: {}", header.event_type, header.event_id);
+            (event, &header.event_type)
+        }
     };
     let ret = match event {
         Event::AddOrRemoveBot(e) => on_add_or_remove_bot(&store, &client, &event_type, e).await,
@@ -56,18 +151,54 @@ async fn on_add_or_remove_bot(
 
 async fn on_text_message(store: &Store, client: &Client, msg: &ReceivedMessage) -> Result<()> {
     debug!("on text message");
-    let text = match msg.message.chat_type {
-        ChatType::P2p => "请在群中@我".to_string(),
-        ChatType::Group => format!(
-            "邮箱地址：{}\n\n这个邮箱的邮件会自动转发到当前群",
-            store.mail_for_chat(msg.message.chat_id.as_ref().unwrap())?
+    let chat_id = match (&msg.message.chat_type, &msg.message.chat_id) {
+        (ChatType::Group, Some(chat_id)) => chat_id,
+        _ => {
+            let text = "请在群中@我".to_string();
+            return client
+                .reply_text_message_async(msg.message.message_id.clone(), text)
+                .await;
+        }
+    };
+    let text = match command(&msg.message).as_deref() {
+        Some("/apikey") => {
+            let key = api::issue_api_key(store, chat_id)?;
+            format!(
+                "API Key：{}\n\n请求时带上 Authorization: Bearer <key>，可以查询和删除本群收到的邮件。\n再次发送 /apikey 会生成新 key，旧 key 立即失效",
+                key
+            )
+        }
+        Some("/smtp") => {
+            let credential = issue_credential(store, chat_id)?;
+            format!(
+                "SMTP 用户名：{}\nSMTP 密码：{}\n\n请通过 SMTPS 端口认证（AUTH PLAIN/LOGIN），认证后只能发往本群，且不做垃圾邮件检查。\n再次发送 /smtp 会生成新密码，旧密码立即失效",
+                credential.username, credential.password
+            )
+        }
+        _ => format!(
+            "邮箱地址：{}\n\n这个邮箱的邮件会自动转发到当前群\n发送 /smtp 获取 SMTP 认证账号，发送 /apikey 获取 API key",
+            store.mail_for_chat(chat_id)?
         ),
     };
 
-    client
-        .reply_text_message_async(msg.message.message_id.clone(), text)
-        .await?;
-    Ok(())
+    // keys and passwords only ever go to the chat they were issued for, not
+    // to whatever message the event points at
+    client.send_text_message_async(chat_id.clone(), text).await
+}
+
+/// The message text with mentions stripped, if it is a `/command`.
+fn command(message: &EventMessage) -> Option<String> {
+    let content: serde_json::Value = serde_json::from_str(&message.content).ok()?;
+    let mut text = content.get("text")?.as_str()?.to_string();
+    for mention in &message.mentions {
+        text = text.replace(&mention.key, "");
+    }
+    let text = text.trim();
+    if text.starts_with('/') {
+        Some(text.to_string())
+    } else {
+        None
+    }
 }
 
 async fn challenge(req: web::Json<Challenge>) -> web::Json<Challenge> {
@@ -78,67 +209,177 @@ async fn index() -> impl Responder {
     "hello"
 }
 
-#[derive(Serialize, Deserialize)]
-struct MailQuery {
-    ts: String,
-    sign: String,
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ViewQuery {
+    images: bool,
+}
+
+/// Checks the signed link, and the user when login is enabled.
+async fn check_access(
+    req: &HttpRequest,
+    mail_id: &str,
+    query: &LinkQuery,
+    store: &Store,
+    url_gen: &MailUrlGen,
+) -> actix_web::Result<()> {
+    url_gen.check(mail_id, query).map_err(ErrorForbidden)?;
+    let revoked = {
+        let (store, mail_id) = (store.clone(), mail_id.to_string());
+        blocking(move || store.mail_links_revoked(&mail_id).map_err(WebError::from)).await?
+    };
+    if revoked {
+        return Err(ErrorForbidden("link revoked"));
+    }
+    let login = req
+        .app_data::<web::Data<Option<WebLogin>>>()
+        .and_then(|login| Option::as_ref(login));
+    if let Some(login) = login {
+        let client = req
+            .app_data::<web::Data<Client>>()
+            .expect("client is registered");
+        login.authorize(req, store, client, mail_id).await?;
+    }
+    Ok(())
+}
+
+/// Loads a mail of up to the max message size, off the async workers.
+async fn open_mail(
+    req: &HttpRequest,
+    mail_id: &str,
+    query: &LinkQuery,
+    store: &Store,
+    url_gen: &MailUrlGen,
+) -> actix_web::Result<Vec<u8>> {
+    check_access(req, mail_id, query, store, url_gen).await?;
+    let (store, mail_id) = (store.clone(), mail_id.to_string());
+    blocking(move || {
+        store
+            .get_mail(&mail_id)?
+            .ok_or_else(|| WebError::not_found("mail"))
+    })
+    .await
+}
+
+async fn open_parsed_mail(
+    req: &HttpRequest,
+    mail_id: &str,
+    query: &LinkQuery,
+    store: &Store,
+    url_gen: &MailUrlGen,
+) -> actix_web::Result<ParsedMail> {
+    check_access(req, mail_id, query, store, url_gen).await?;
+    let (store, mail_id) = (store.clone(), mail_id.to_string());
+    blocking(move || {
+        let body = store
+            .get_mail(&mail_id)?
+            .ok_or_else(|| WebError::not_found("mail"))?;
+        parse_mail(&body).map_err(|e| {
+            WebError(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("parse mail error: {}", e),
+            )
+        })
+    })
+    .await
 }
 
 async fn mail(
+    req: HttpRequest,
     mail_id: web::Path<String>,
-    query: web::Query<MailQuery>,
+    query: web::Query<LinkQuery>,
+    view: web::Query<ViewQuery>,
     store: web::Data<Store>,
     url_gen: web::Data<MailUrlGen>,
-) -> impl Responder {
-    if !url_gen.check_sign(&*mail_id, &query.ts, &query.sign) {
-        return HttpResponse::Forbidden().body("invalid sign");
-    }
-    let body = match store.get_mail(&*mail_id) {
-        Ok(Some(b)) => b,
-        Ok(None) => return HttpResponse::NotFound().finish(),
-        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
-    };
-    HttpResponse::Ok()
+) -> actix_web::Result<HttpResponse> {
+    let parsed = open_parsed_mail(&req, &mail_id, &query, &store, &url_gen).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .append_header((
+            "Content-Security-Policy",
+            mail_view::content_security_policy(view.images),
+        ))
+        // keep the signed url away from remote image hosts
+        .append_header(("Referrer-Policy", "no-referrer"))
+        .body(mail_view::render(
+            &mail_id,
+            &parsed,
+            &query.to_query(),
+            view.images,
+        )))
+}
+
+async fn raw_mail(
+    req: HttpRequest,
+    mail_id: web::Path<String>,
+    query: web::Query<LinkQuery>,
+    store: web::Data<Store>,
+    url_gen: web::Data<MailUrlGen>,
+) -> actix_web::Result<HttpResponse> {
+    let body = open_mail(&req, &mail_id, &query, &store, &url_gen).await?;
+    Ok(HttpResponse::Ok()
         .content_type("application/octet-stream")
         .append_header((
             "Content-Disposition",
             format!("attachment; filename=\"{}.eml\"", mail_id),
         ))
-        .body(body)
+        .body(body))
 }
 
-#[derive(Clone)]
-pub struct MailUrlGen {
-    secret: String,
-    domain: String,
-}
-
-impl MailUrlGen {
-    pub fn new(domain: String, secret: String) -> Self {
-        MailUrlGen { secret, domain }
+async fn attachment(
+    req: HttpRequest,
+    path: web::Path<(String, usize)>,
+    query: web::Query<LinkQuery>,
+    store: web::Data<Store>,
+    url_gen: web::Data<MailUrlGen>,
+) -> actix_web::Result<HttpResponse> {
+    let (mail_id, n) = path.into_inner();
+    let mut parsed = open_parsed_mail(&req, &mail_id, &query, &store, &url_gen).await?;
+    if n >= parsed.attachments.len() {
+        return Err(ErrorNotFound("attachment not found"));
     }
+    Ok(mail_view::attachment_response(
+        parsed.attachments.swap_remove(n),
+    ))
+}
 
-    pub fn gen_url(&self, id: &str) -> String {
-        let ts = std::time::SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let sign = self.compute_sign(id, ts);
-        format!(
-            "http://{}/mail/{}?ts={}&sign={}",
-            &self.domain, id, ts, sign
-        )
-    }
+pub struct HttpConfig {
+    pub listen: Vec<String>,
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Every listener speaks HTTPS when set.
+    pub tls: Option<Arc<ServerConfig>>,
+    pub verification_token: String,
+}
 
-    fn compute_sign(&self, id: &str, ts: impl Display) -> String {
-        let digest = md5::compute(format!("{}{}{}", id, ts, &self.secret).as_bytes());
-        format!("{:x}", digest)
+impl HttpConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let tls = match (config::var("HTTP_TLS_CERT"), config::var("HTTP_TLS_KEY")) {
+            (Ok(cert), Ok(key)) if !cert.is_empty() && !key.is_empty() => Some(
+                tls::load_server_config(Path::new(&cert), Path::new(&key))
+                    .context("HTTP_TLS_CERT")?,
+            ),
+            (Ok(s), _) | (_, Ok(s)) if !s.is_empty() => {
+                return Err(anyhow!(
+                    "`HTTP_TLS_CERT` and `HTTP_TLS_KEY` must be set together"
+                ))
+            }
+            _ => None,
+        };
+        // without it anyone could post events and have keys minted for any chat
+        let verification_token = config::required("FEISHU_VERIFICATION_TOKEN")?;
+        Ok(HttpConfig {
+            listen: listen::addrs_from_env("HTTP_LISTEN", "0.0.0.0:8088"),
+            proxy_protocol: ProxyProtocol::from_env("HTTP_PROXY_PROTOCOL")?,
+            tls,
+            verification_token,
+        })
     }
+}
 
-    pub fn check_sign(&self, id: &str, ts: &str, sign: &str) -> bool {
-        let real_sign = self.compute_sign(id, ts);
-        real_sign == sign
-    }
+async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(crate::metrics::render())
 }
 
 #[actix_web::main]
@@ -146,20 +387,139 @@ pub(crate) async fn serve(
     client: Client,
     store: Store,
     mail_url_gen: MailUrlGen,
+    web_login: Option<WebLogin>,
+    config: HttpConfig,
+    listeners: Vec<TcpListener>,
+    shutdown: Shutdown,
 ) -> std::io::Result<()> {
-    info!("Bot Server: 0.0.0.0:8088");
-    HttpServer::new(move || {
+    let app_shutdown = shutdown.clone();
+    let verification = Data::new(VerificationToken(config.verification_token.clone()));
+    let app = move || {
         App::new()
             .wrap(actix_web::middleware::Logger::default())
             .app_data(Data::new(client.clone()))
             .app_data(Data::new(store.clone()))
             .app_data(Data::new(mail_url_gen.clone()))
+            .app_data(Data::new(web_login.clone()))
+            .app_data(Data::new(app_shutdown.clone()))
+            .app_data(verification.clone())
             .route("/challenge", web::post().to(challenge))
             .route("/event", web::post().to(event))
+            .route("/auth/login", web::get().to(login::login))
+            .route("/auth/callback", web::get().to(login::callback))
+            .route("/auth/logout", web::get().to(login::logout))
+            .route("/admin", web::get().to(admin::chats))
+            .route("/admin/chats/{id}", web::get().to(admin::chat))
+            .route("/admin/chats/{id}", web::post().to(admin::save_chat))
             .route("/mail/{id}", web::get().to(mail))
+            .route("/mail/{id}/raw", web::get().to(raw_mail))
+            .route("/mail/{id}/attachments/{n}", web::get().to(attachment))
+            .route("/api/v1/mails", web::get().to(api::list_mails))
+            .route("/api/v1/mails/{id}", web::get().to(api::get_mail))
+            .route("/api/v1/mails/{id}", web::delete().to(api::delete_mail))
+            .route(
+                "/api/v1/mails/{id}/attachments/{n}",
+                web::get().to(api::get_attachment),
+            )
+            .route("/metrics", web::get().to(metrics))
+            .route("/healthz", web::get().to(health::healthz))
+            .route("/readyz", web::get().to(health::readyz))
             .route("/", web::get().to(index))
-    })
-    .bind("0.0.0.0:8088")?
-    .run()
-    .await
+    };
+    let Some(proxy_protocol) = config.proxy_protocol else {
+        let mut server = HttpServer::new(app).disable_signals();
+        for listener in listeners {
+            server = match &config.tls {
+                Some(tls) => server.listen_rustls_0_23(listener, ServerConfig::clone(tls))?,
+                None => server.listen(listener)?,
+            };
+        }
+        let server = server.run();
+        rt::spawn(stop_on_shutdown(server.handle(), shutdown));
+        return server.await;
+    };
+    // HttpServer gives no access to the stream before the request is parsed,
+    // so strip the PROXY header in front of a plain HttpService instead.
+    let mut server = actix_server::Server::build().disable_signals();
+    if let Some(tls) = config.tls {
+        // the header comes before the TLS handshake
+        let mut tls = ServerConfig::clone(&tls);
+        tls.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(tls));
+        let factory = move || {
+            let proxy_protocol = proxy_protocol.clone();
+            let acceptor = acceptor.clone();
+            fn_service(move |mut io: TcpStream| {
+                let proxy_protocol = proxy_protocol.clone();
+                let acceptor = acceptor.clone();
+                async move {
+                    let peer = io.peer_addr()?;
+                    let addr = timeout(HEADER_TIMEOUT, proxy_protocol.accept_async(peer, &mut io))
+                        .await
+                        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "proxy header"))??;
+                    let io = timeout(HEADER_TIMEOUT, acceptor.accept(io))
+                        .await
+                        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "tls handshake"))??;
+                    let protocol = match io.get_ref().1.alpn_protocol() {
+                        Some(b"h2") => Protocol::Http2,
+                        _ => Protocol::Http1,
+                    };
+                    Ok::<_, DispatchError>((io, protocol, Some(addr)))
+                }
+            })
+            .and_then(
+                HttpService::build()
+                    .secure()
+                    .finish(map_config(app(), |_| AppConfig::default())),
+            )
+        };
+        for listener in listeners {
+            server = server.listen("mailhook-https", listener, factory.clone())?;
+        }
+    } else {
+        let factory = move || {
+            let proxy_protocol = proxy_protocol.clone();
+            fn_service(move |mut io: TcpStream| {
+                let proxy_protocol = proxy_protocol.clone();
+                async move {
+                    let peer = io.peer_addr()?;
+                    let addr = timeout(HEADER_TIMEOUT, proxy_protocol.accept_async(peer, &mut io))
+                        .await
+                        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "proxy header"))??;
+                    Ok::<_, DispatchError>((io, Protocol::Http1, Some(addr)))
+                }
+            })
+            .and_then(HttpService::build().finish(map_config(app(), |_| AppConfig::default())))
+        };
+        for listener in listeners {
+            server = server.listen("mailhook-http", listener, factory.clone())?;
+        }
+    }
+    let server = server.run();
+    rt::spawn(stop_on_shutdown(server.handle(), shutdown));
+    server.await
+}
+
+/// Signals are handled in `main`, the server follows the shared shutdown state
+/// and lets in-flight requests finish.
+async fn stop_on_shutdown(handle: ServerHandle, shutdown: Shutdown) {
+    while !shutdown.is_stopping() {
+        rt::time::sleep(Duration::from_millis(200)).await;
+    }
+    info!("http server stopping");
+    handle.stop(true).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verification_token() {
+        let token = VerificationToken("v3rification".to_string());
+        assert!(token.matches("v3rification"));
+        assert!(!token.matches("v3rificatioN"));
+        assert!(!token.matches("v3rif"));
+        assert!(!token.matches(""));
+    }
 }
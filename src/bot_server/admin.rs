@@ -0,0 +1,391 @@
+use crate::bot_server::feishu_client::Client;
+use crate::bot_server::mail_view::escape;
+use crate::bot_server::{blocking, WebError, WebLogin};
+use crate::store::{ChatSettings, RenderMode, Store};
+use actix_web::error::{ErrorBadRequest, ErrorForbidden, ErrorInternalServerError, ErrorNotFound};
+use actix_web::http::header::LOCATION;
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Data};
+use actix_web::{HttpRequest, HttpResponse};
+use log::{info, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Mails received in this window count as recent on the chat list.
+const RECENT_DAYS: i64 = 7;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:0 auto;max-width:60em;padding:1em}\
+th,td{text-align:left;padding:.3em 1em .3em 0;vertical-align:top}th{color:#666}\
+label{display:block;margin:1em 0 .3em}textarea{width:100%;max-width:30em}\
+.notice{background:#e8f5e9;padding:.5em}.hint{color:#666;font-size:.9em}";
+
+fn page(title: &str, body: &str) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .append_header((
+            "Content-Security-Policy",
+            "default-src 'none'; style-src 'unsafe-inline'; form-action 'self'; frame-ancestors 'none'",
+        ))
+        .body(format!(
+            "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+             <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+             <title>{}</title><style>{}</style></head><body>{}</body></html>",
+            escape(title),
+            STYLE,
+            body
+        ))
+}
+
+fn enabled(login: &Option<WebLogin>) -> actix_web::Result<&WebLogin> {
+    login
+        .as_ref()
+        .ok_or_else(|| ErrorNotFound("login is not enabled"))
+}
+
+/// Chat names from Feishu, the pages fall back to the chat ids if that fails.
+async fn chat_names(client: &Client) -> HashMap<String, String> {
+    let client = client.clone();
+    match web::block(move || client.chat_names()).await {
+        Ok(Ok(names)) => names,
+        Ok(Err(e)) => {
+            warn!("get chat names error: {}", e);
+            HashMap::new()
+        }
+        Err(e) => {
+            warn!("get chat names error: {}", e);
+            HashMap::new()
+        }
+    }
+}
+
+/// The chat's own address followed by its aliases.
+fn addresses(chat_id: &str, aliases: &[String], domain: &str) -> String {
+    std::iter::once(chat_id)
+        .chain(aliases.iter().map(String::as_str))
+        .map(|name| escape(&format!("{}@{}", name, domain)))
+        .collect::<Vec<_>>()
+        .join("<br>")
+}
+
+/// Short description of the settings that differ from the defaults.
+fn describe(settings: &ChatSettings) -> String {
+    let mut parts = vec![];
+    if settings.muted {
+        parts.push("muted".to_string());
+    }
+    if !settings.blocked_senders.is_empty() {
+        parts.push(format!(
+            "{} blocked senders",
+            settings.blocked_senders.len()
+        ));
+    }
+    if settings.render_mode == RenderMode::Links {
+        parts.push("attachment links".to_string());
+    }
+    if let Some(days) = settings.retention_days {
+        parts.push(format!("kept {} days", days));
+    }
+    if parts.is_empty() {
+        "default".to_string()
+    } else {
+        parts.join(", ")
+    }
+}
+
+pub async fn chats(
+    req: HttpRequest,
+    store: Data<Store>,
+    client: Data<Client>,
+    login: Data<Option<WebLogin>>,
+) -> actix_web::Result<HttpResponse> {
+    let login = enabled(&login)?;
+    login.admin(&req)?;
+    let names = chat_names(&client).await;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(ErrorInternalServerError)?
+        .as_secs() as i64;
+    let chats = store
+        .chat_summaries(now - RECENT_DAYS * 24 * 3600)
+        .map_err(ErrorInternalServerError)?;
+    let mut body = format!(
+        "<h2>Chats</h2><table><tr><th>Chat</th><th>Addresses</th><th>Last {} days</th>\
+         <th>Stored</th><th>Settings</th></tr>",
+        RECENT_DAYS
+    );
+    for chat in &chats {
+        body.push_str(&format!(
+            "<tr><td><a href=\"{}/admin/chats/{}\">{}</a></td><td>{}</td>\
+             <td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(login.base_url()),
+            escape(&chat.id),
+            escape(names.get(&chat.id).unwrap_or(&chat.id)),
+            addresses(&chat.id, &chat.aliases, store.mail_domain()),
+            chat.recent_mails,
+            chat.total_mails,
+            describe(&chat.settings)
+        ));
+    }
+    body.push_str("</table>");
+    if chats.is_empty() {
+        body.push_str("<p>The bot is not in any chat yet.</p>");
+    }
+    Ok(page("Chats", &body))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ChatQuery {
+    saved: bool,
+}
+
+pub async fn chat(
+    req: HttpRequest,
+    chat_id: web::Path<String>,
+    query: web::Query<ChatQuery>,
+    store: Data<Store>,
+    client: Data<Client>,
+    login: Data<Option<WebLogin>>,
+) -> actix_web::Result<HttpResponse> {
+    let login = enabled(&login)?;
+    let open_id = login.admin(&req)?;
+    let settings = store
+        .chat_settings(&chat_id)
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorNotFound("chat not found"))?;
+    let aliases = store
+        .chat_aliases(&chat_id)
+        .map_err(ErrorInternalServerError)?;
+    let name = chat_names(&client)
+        .await
+        .remove(chat_id.as_str())
+        .unwrap_or_else(|| chat_id.to_string());
+    let base_url = escape(login.base_url());
+    let id = escape(&chat_id);
+    let mut body = format!(
+        "<p><a href=\"{}/admin\">All chats</a></p><h2>{}</h2><p>{}</p>",
+        base_url,
+        escape(&name),
+        addresses(&chat_id, &aliases, store.mail_domain())
+    );
+    if query.saved {
+        body.push_str("<p class=\"notice\">Saved.</p>");
+    }
+    let option = |mode: RenderMode, label: &str| {
+        format!(
+            "<option value=\"{}\"{}>{}</option>",
+            mode.as_str(),
+            if settings.render_mode == mode {
+                " selected"
+            } else {
+                ""
+            },
+            label
+        )
+    };
+    body.push_str(&format!(
+        "<form method=\"post\" action=\"{}/admin/chats/{}\">\
+         <input type=\"hidden\" name=\"csrf\" value=\"{}\">\
+         <label for=\"aliases\">Aliases</label>\
+         <textarea id=\"aliases\" name=\"aliases\" rows=\"3\">{}</textarea>\
+         <div class=\"hint\">Extra addresses at @{}, one name per line.</div>\
+         <label><input type=\"checkbox\" name=\"muted\" value=\"true\"{}> Muted</label>\
+         <div class=\"hint\">Mails are still stored and readable on the web, they are just not posted to the chat.</div>\
+         <label for=\"blocked_senders\">Blocked senders</label>\
+         <textarea id=\"blocked_senders\" name=\"blocked_senders\" rows=\"5\">{}</textarea>\
+         <div class=\"hint\">One per line, a whole address or <code>@domain</code>.</div>\
+         <label for=\"render_mode\">Attachments</label>\
+         <select id=\"render_mode\" name=\"render_mode\">{}{}</select>\
+         <label for=\"retention_days\">Keep mails for days</label>\
+         <input id=\"retention_days\" name=\"retention_days\" value=\"{}\" inputmode=\"numeric\">\
+         <div class=\"hint\">Expired mails are removed every hour, empty keeps them until <code>mailhook purge</code>.</div>\
+         <p><button type=\"submit\">Save</button></p></form>",
+        base_url,
+        id,
+        login.csrf_token(&open_id),
+        escape(&aliases.join("\n")),
+        escape(store.mail_domain()),
+        if settings.muted { " checked" } else { "" },
+        escape(&settings.blocked_senders.join("\n")),
+        option(RenderMode::Full, "Upload as files"),
+        option(RenderMode::Links, "Download links only"),
+        settings
+            .retention_days
+            .map(|days| days.to_string())
+            .unwrap_or_default()
+    ));
+    Ok(page(&name, &body))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ChatForm {
+    csrf: String,
+    aliases: String,
+    muted: Option<String>,
+    blocked_senders: String,
+    render_mode: String,
+    retention_days: String,
+}
+
+impl ChatForm {
+    fn aliases(&self) -> Result<Vec<String>, String> {
+        let mut aliases = vec![];
+        for alias in self.aliases.lines().map(str::trim) {
+            if alias.is_empty() {
+                continue;
+            }
+            let valid = alias
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+            if !valid || alias.len() > 64 {
+                return Err(format!(
+                    "`{}` is not a valid alias, use letters, digits, `.`, `-` and `_`",
+                    alias
+                ));
+            }
+            let alias = alias.to_lowercase();
+            if !aliases.contains(&alias) {
+                aliases.push(alias);
+            }
+        }
+        Ok(aliases)
+    }
+
+    fn settings(&self) -> Result<ChatSettings, String> {
+        let mut blocked_senders = vec![];
+        for sender in self.blocked_senders.lines().map(str::trim) {
+            if sender.is_empty() {
+                continue;
+            }
+            if !sender.contains('@') || sender.contains(char::is_whitespace) {
+                return Err(format!("`{}` is not an address or @domain", sender));
+            }
+            blocked_senders.push(sender.to_string());
+        }
+        let render_mode = RenderMode::parse(&self.render_mode)
+            .ok_or_else(|| format!("unknown attachment mode `{}`", self.render_mode))?;
+        let retention_days = match self.retention_days.trim() {
+            "" => None,
+            days => match days.parse() {
+                Ok(days) if days > 0 => Some(days),
+                _ => return Err("days to keep mails must be a positive number".to_string()),
+            },
+        };
+        Ok(ChatSettings {
+            muted: self.muted.is_some(),
+            blocked_senders,
+            render_mode,
+            retention_days,
+        })
+    }
+}
+
+pub async fn save_chat(
+    req: HttpRequest,
+    chat_id: web::Path<String>,
+    form: web::Form<ChatForm>,
+    store: Data<Store>,
+    login: Data<Option<WebLogin>>,
+) -> actix_web::Result<HttpResponse> {
+    let login = enabled(&login)?;
+    let open_id = login.admin(&req)?;
+    if !login.check_csrf(&open_id, &form.csrf) {
+        return Err(ErrorForbidden("invalid form token, reload the page"));
+    }
+    let settings = form.settings().map_err(ErrorBadRequest)?;
+    let aliases = form.aliases().map_err(ErrorBadRequest)?;
+    {
+        let (store, chat_id) = (store.get_ref().clone(), chat_id.to_string());
+        let (settings, aliases) = (settings.clone(), aliases.clone());
+        blocking(move || {
+            // refused before anything is written, the update itself is one transaction
+            for alias in &aliases {
+                match store.rcpt_chat(alias)? {
+                    Some(owner) if owner != chat_id => {
+                        return Err(WebError(
+                            StatusCode::CONFLICT,
+                            format!("`{}` is already used by another chat", alias),
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+            if !store.update_chat(&chat_id, &settings, &aliases)? {
+                return Err(WebError::not_found("chat"));
+            }
+            Ok(())
+        })
+        .await?;
+    }
+    info!(
+        "admin {} changed chat {}: {:?}, aliases: {:?}",
+        open_id, chat_id, settings, aliases
+    );
+    Ok(HttpResponse::SeeOther()
+        .insert_header((
+            LOCATION,
+            format!(
+                "{}/admin/chats/{}?saved=true",
+                login.base_url(),
+                chat_id.as_str()
+            ),
+        ))
+        .finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chat_form() {
+        let form = ChatForm {
+            csrf: String::new(),
+            aliases: " Ops\n\nalerts\nops\n".to_string(),
+            muted: Some("true".to_string()),
+            blocked_senders: " spam@a.com \n\n@b.com\n".to_string(),
+            render_mode: "links".to_string(),
+            retention_days: "30".to_string(),
+        };
+        assert_eq!(form.aliases().unwrap(), vec!["ops", "alerts"]);
+        let settings = form.settings().unwrap();
+        assert!(settings.muted);
+        assert_eq!(settings.blocked_senders, vec!["spam@a.com", "@b.com"]);
+        assert_eq!(settings.render_mode, RenderMode::Links);
+        assert_eq!(settings.retention_days, Some(30));
+        assert_eq!(
+            describe(&settings),
+            "muted, 2 blocked senders, attachment links, kept 30 days"
+        );
+
+        let form = ChatForm {
+            render_mode: "full".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(form.settings().unwrap(), ChatSettings::default());
+        assert_eq!(describe(&ChatSettings::default()), "default");
+        for alias in ["a b", "a@b", "点"] {
+            let form = ChatForm {
+                aliases: alias.to_string(),
+                ..Default::default()
+            };
+            assert!(form.aliases().is_err());
+        }
+        for (senders, mode, days) in [
+            ("not an address", "full", ""),
+            ("", "card", ""),
+            ("", "full", "0"),
+            ("", "full", "-1"),
+        ] {
+            let form = ChatForm {
+                blocked_senders: senders.to_string(),
+                render_mode: mode.to_string(),
+                retention_days: days.to_string(),
+                ..Default::default()
+            };
+            assert!(form.settings().is_err());
+        }
+    }
+}
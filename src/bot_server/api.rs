@@ -0,0 +1,299 @@
+use crate::bot_server::MailUrlGen;
+use crate::bot_server::{self, mail_view};
+use crate::smtp_server::mail::{parse_mail, ParsedMail};
+use crate::store::{MailFilter, MailSummary, Store};
+use actix_web::error::InternalError;
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Data};
+use actix_web::{HttpRequest, HttpResponse};
+use anyhow::Result;
+use log::info;
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const KEY_PREFIX: &str = "mhk_";
+const DEFAULT_LIMIT: u32 = 20;
+const MAX_LIMIT: u32 = 100;
+
+/// Issues a new API key for the chat, replacing the previous one. Only the
+/// SHA-256 is kept, the key is shown once when issued.
+pub fn issue_api_key(store: &Store, chat_id: &str) -> Result<String> {
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    let key = format!("{}{}", KEY_PREFIX, hex::encode(secret));
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    store.set_api_key(chat_id, &hash_key(&key), now)?;
+    info!("issue api key for chat: {}", chat_id);
+    Ok(key)
+}
+
+fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn api_error(status: StatusCode, message: impl Display) -> actix_web::Error {
+    let message = message.to_string();
+    let mut resp = HttpResponse::build(status);
+    if status == StatusCode::UNAUTHORIZED {
+        resp.append_header(("WWW-Authenticate", "Bearer"));
+    }
+    InternalError::from_response(message.clone(), resp.json(json!({ "error": message }))).into()
+}
+
+/// An error from the blocking pool, `actix_web::Error` can't be sent across threads.
+#[derive(Debug)]
+struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn not_found(what: &str) -> Self {
+        ApiError(StatusCode::NOT_FOUND, format!("{} not found", what))
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+impl From<ApiError> for actix_web::Error {
+    fn from(e: ApiError) -> Self {
+        api_error(e.0, e.1)
+    }
+}
+
+/// The shared blocking helper with the error type pinned, so `?` in the closures
+/// knows what to convert to.
+async fn blocking<T, F>(f: F) -> actix_web::Result<T>
+where
+    F: FnOnce() -> Result<T, ApiError> + Send + 'static,
+    T: Send + 'static,
+{
+    bot_server::blocking(f).await
+}
+
+fn bearer_key(req: &HttpRequest) -> actix_web::Result<String> {
+    req.headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|key| key.starts_with(KEY_PREFIX))
+        .map(str::to_string)
+        .ok_or_else(|| api_error(StatusCode::UNAUTHORIZED, "missing api key"))
+}
+
+/// The chat the bearer token belongs to.
+fn authenticate(store: &Store, key: &str) -> Result<String, ApiError> {
+    store
+        .chat_for_api_key(&hash_key(key))?
+        .ok_or_else(|| ApiError(StatusCode::UNAUTHORIZED, "invalid api key".to_string()))
+}
+
+/// Checks the mail was sent to the chat, other chats' mails look like missing ones.
+fn check_mail(store: &Store, chat_id: &str, id: &str) -> Result<(), ApiError> {
+    if store.chat_has_mail(chat_id, id)? {
+        Ok(())
+    } else {
+        Err(ApiError::not_found("mail"))
+    }
+}
+
+/// The stored mail of the chat, parsed.
+fn chat_mail(store: &Store, chat_id: &str, id: &str) -> Result<(Vec<u8>, ParsedMail), ApiError> {
+    check_mail(store, chat_id, id)?;
+    let body = store
+        .get_mail(id)?
+        .ok_or_else(|| ApiError::not_found("mail"))?;
+    let mail = parse_mail(&body).map_err(|e| {
+        ApiError(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("parse mail error: {}", e),
+        )
+    })?;
+    Ok((body, mail))
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ListQuery {
+    limit: u32,
+    offset: u32,
+    from: Option<String>,
+    subject: Option<String>,
+    /// Unix timestamps of the receive time, `until` is exclusive.
+    since: Option<i64>,
+    until: Option<i64>,
+}
+
+impl Default for ListQuery {
+    fn default() -> Self {
+        ListQuery {
+            limit: DEFAULT_LIMIT,
+            offset: 0,
+            from: None,
+            subject: None,
+            since: None,
+            until: None,
+        }
+    }
+}
+
+fn summary_json(mail: &MailSummary) -> Value {
+    json!({
+        "id": mail.id,
+        "mail_from": mail.mail_from,
+        "subject": mail.subject,
+        "received_at": mail.received_at,
+        "size": mail.size,
+    })
+}
+
+pub async fn list_mails(
+    req: HttpRequest,
+    query: web::Query<ListQuery>,
+    store: Data<Store>,
+) -> actix_web::Result<HttpResponse> {
+    let key = bearer_key(&req)?;
+    let store = store.get_ref().clone();
+    let query = query.into_inner();
+    let limit = query.limit.clamp(1, MAX_LIMIT);
+    let filter = MailFilter {
+        from: query.from.filter(|s| !s.is_empty()),
+        subject: query.subject.filter(|s| !s.is_empty()),
+        since: query.since,
+        until: query.until,
+    };
+    let (total, mails) = blocking(move || {
+        let chat_id = authenticate(&store, &key)?;
+        Ok(store.list_chat_mails(&chat_id, &filter, limit, query.offset)?)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(json!({
+        "total": total,
+        "limit": limit,
+        "offset": query.offset,
+        "mails": mails.iter().map(summary_json).collect::<Vec<_>>(),
+    })))
+}
+
+pub async fn get_mail(
+    req: HttpRequest,
+    mail_id: web::Path<String>,
+    store: Data<Store>,
+    url_gen: Data<MailUrlGen>,
+) -> actix_web::Result<HttpResponse> {
+    let key = bearer_key(&req)?;
+    let store = store.get_ref().clone();
+    let id = mail_id.clone();
+    let (body, mail, envelope) = blocking(move || {
+        let chat_id = authenticate(&store, &key)?;
+        let (body, mail) = chat_mail(&store, &chat_id, &id)?;
+        let envelope = store.get_mail_envelope(&id)?.unwrap_or_default();
+        Ok((body, mail, envelope))
+    })
+    .await?;
+    let attachments: Vec<_> = mail
+        .attachments
+        .iter()
+        .enumerate()
+        .map(|(n, attachment)| {
+            json!({
+                "index": n,
+                "filename": attachment.filename,
+                "mime_type": attachment.mime_type,
+                "size": attachment.data.len(),
+            })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(json!({
+        "id": *mail_id,
+        "mail_from": envelope.mail_from,
+        "rcpts": envelope.rcpts,
+        "received_at": envelope.received_at,
+        "size": body.len(),
+        "subject": mail.subject,
+        "from": mail.from,
+        "to": mail.to,
+        "cc": mail.cc,
+        "date": mail.date,
+        "text": mail.text,
+        "html": mail.html,
+        "attachments": attachments,
+        "url": url_gen.gen_url(&mail_id),
+    })))
+}
+
+pub async fn get_attachment(
+    req: HttpRequest,
+    path: web::Path<(String, usize)>,
+    store: Data<Store>,
+) -> actix_web::Result<HttpResponse> {
+    let key = bearer_key(&req)?;
+    let store = store.get_ref().clone();
+    let (mail_id, n) = path.into_inner();
+    let attachment = blocking(move || {
+        let chat_id = authenticate(&store, &key)?;
+        let (_, mut mail) = chat_mail(&store, &chat_id, &mail_id)?;
+        if n >= mail.attachments.len() {
+            return Err(ApiError::not_found("attachment"));
+        }
+        Ok(mail.attachments.swap_remove(n))
+    })
+    .await?;
+    Ok(mail_view::attachment_response(attachment))
+}
+
+pub async fn delete_mail(
+    req: HttpRequest,
+    mail_id: web::Path<String>,
+    store: Data<Store>,
+) -> actix_web::Result<HttpResponse> {
+    let key = bearer_key(&req)?;
+    let store = store.get_ref().clone();
+    let id = mail_id.clone();
+    let chat_id = blocking(move || {
+        let chat_id = authenticate(&store, &key)?;
+        if !store.delete_chat_mail(&chat_id, &id)? {
+            return Err(ApiError::not_found("mail"));
+        }
+        Ok(chat_id)
+    })
+    .await?;
+    info!("api: chat {} deleted mail {}", chat_id, mail_id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_authenticate() {
+        let store = Store::in_memory().unwrap();
+        let key = issue_api_key(&store, "chat").unwrap();
+        assert!(key.starts_with(KEY_PREFIX));
+        let req = TestRequest::default()
+            .insert_header(("Authorization", format!("Bearer {}", key)))
+            .to_http_request();
+        assert_eq!(bearer_key(&req).unwrap(), key);
+        assert_eq!(authenticate(&store, &key).unwrap(), "chat");
+
+        let e = authenticate(&store, "mhk_wrong").unwrap_err();
+        assert_eq!(e.0, StatusCode::UNAUTHORIZED);
+        let e = bearer_key(&TestRequest::default().to_http_request()).unwrap_err();
+        assert_eq!(
+            e.as_response_error().status_code(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        // a new key replaces the old one
+        issue_api_key(&store, "chat").unwrap();
+        assert!(authenticate(&store, &key).is_err());
+    }
+}
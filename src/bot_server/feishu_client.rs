@@ -1,18 +1,83 @@
+use crate::{config, logging, metrics, telemetry};
 use actix_web::web::block;
-use anyhow::{ensure, Result};
-use log::info;
+use anyhow::{anyhow, ensure, Result};
+use log::{debug, info};
+use opentelemetry::{ContextGuard, KeyValue};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use ureq::json;
 use ureq_multipart::MultipartBuilder;
 
+/// Tokens are refreshed this long before Feishu expires them.
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(300);
+
 #[derive(Clone)]
 pub struct Client {
     app_id: String,
     app_secret: String,
+    dry_run: Option<DryRun>,
+    /// Tenant access token and when to refresh it, shared by all clones.
+    token: Arc<Mutex<Option<(String, Instant)>>>,
+}
+
+/// Where a dry-run client records the requests it would have sent to Feishu.
+#[derive(Clone)]
+pub enum DryRun {
+    Log,
+    File(Arc<Mutex<File>>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl DryRun {
+    /// `FEISHU_DRY_RUN=log` logs the requests, any other value is a JSONL file they are appended to.
+    pub fn from_env() -> Result<Option<Self>> {
+        match config::var("FEISHU_DRY_RUN").as_deref() {
+            Err(_) | Ok("") | Ok("false") => Ok(None),
+            Ok("log") => Ok(Some(DryRun::Log)),
+            Ok(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| anyhow!("open FEISHU_DRY_RUN file {} error: {}", path, e))?;
+                Ok(Some(DryRun::File(Arc::new(Mutex::new(file)))))
+            }
+        }
+    }
+
+    fn record(&self, api: &str, body: Value) -> Result<()> {
+        match self {
+            DryRun::Log => info!(
+                "dry run: {}",
+                json!({ "mail_id": logging::mail_id(), "api": api, "body": summary(&body) })
+            ),
+            DryRun::File(file) => {
+                let ts = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                let line = serde_json::to_string(
+                    &json!({ "ts": ts, "mail_id": logging::mail_id(), "api": api, "body": body }),
+                )?;
+                writeln!(file.lock().unwrap(), "{}", logging::redact(line))?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Message content holds mail text and chat replies with credentials, only its size goes to the log.
+fn summary(body: &Value) -> Value {
+    let mut summary = body.clone();
+    if let Some(content) = summary.get_mut("content") {
+        *content = json!({ "bytes": content.as_str().map_or(0, str::len) });
+    }
+    summary
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum MessageType {
     Text,
@@ -27,6 +92,13 @@ pub enum MessageType {
     ShareUser,
 }
 
+/// `im/v1/files` refuses empty files and files over 30 MB.
+const MAX_FILE_SIZE: usize = 30 * 1024 * 1024;
+
+pub fn can_upload(size: usize) -> bool {
+    size > 0 && size <= MAX_FILE_SIZE
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum FileType {
@@ -51,6 +123,13 @@ struct CreateFileData {
     file_key: String,
 }
 
+/// The Feishu user behind a web login.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoginUser {
+    pub open_id: String,
+    pub name: String,
+}
+
 #[derive(Serialize, Deserialize)]
 struct SendMessageData {
     message_id: String,
@@ -58,7 +137,22 @@ struct SendMessageData {
 
 impl Client {
     pub fn new(app_id: String, app_secret: String) -> Self {
-        Client { app_id, app_secret }
+        Client {
+            app_id,
+            app_secret,
+            dry_run: None,
+            token: Arc::default(),
+        }
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run.is_some()
+    }
+
+    /// With `Some`, requests are recorded instead of sent.
+    pub fn with_dry_run(mut self, dry_run: Option<DryRun>) -> Self {
+        self.dry_run = dry_run;
+        self
     }
 
     pub fn create_file(
@@ -67,6 +161,14 @@ impl Client {
         file_name: String,
         mut data: &[u8],
     ) -> Result<String> {
+        if let Some(dry_run) = &self.dry_run {
+            dry_run.record(
+                "im/v1/files",
+                json!({ "file_type": file_type, "file_name": file_name, "size": data.len() }),
+            )?;
+            return Ok(format!("dry-run-{}", uuid::Uuid::new_v4()));
+        }
+        let _span = request_span("im/v1/files");
         let (content_type, multipart) = MultipartBuilder::new()
             .add_text(
                 "file_type",
@@ -78,11 +180,13 @@ impl Client {
             .add_stream(&mut data, "file", Some(&file_name), None)?
             .finish()?;
         let token = self.get_tenant_access_token()?;
-        let resp: Resp<CreateFileData> = ureq::post("https://open.feishu.cn/open-apis/im/v1/files")
-            .set("Authorization", &format!("Bearer {}", token))
-            .set("Content-Type", &content_type)
-            .send_bytes(&multipart)?
-            .into_json()?;
+        let resp: Resp<CreateFileData> = call(
+            "im/v1/files",
+            ureq::post("https://open.feishu.cn/open-apis/im/v1/files")
+                .set("Authorization", &format!("Bearer {}", token))
+                .set("Content-Type", &content_type)
+                .send_bytes(&multipart),
+        )?;
         ensure!(resp.code == 0, resp.msg);
         Ok(resp.data.file_key)
     }
@@ -93,20 +197,19 @@ impl Client {
         message_type: MessageType,
         content: Value,
     ) -> Result<()> {
-        let c = serde_json::to_string(&content)?;
-        info!("send message: {}", c);
-        let req = json!({
-            "receive_id": chat_id,
-            "msg_type": message_type,
-            "content": c,
-            "uuid": uuid::Uuid::new_v4().to_string()
-        });
+        let req = message_request(chat_id, message_type, &content)?;
+        debug!("send {:?} message to {}", message_type, req["receive_id"]);
+        if let Some(dry_run) = &self.dry_run {
+            return dry_run.record("im/v1/messages", req);
+        }
+        let _span = request_span("im/v1/messages");
         let token = self.get_tenant_access_token()?;
-        let resp: Resp<SendMessageData> =
+        let resp: Resp<SendMessageData> = call(
+            "im/v1/messages",
             ureq::post("https://open.feishu.cn/open-apis/im/v1/messages?receive_id_type=chat_id")
                 .set("Authorization", &format!("Bearer {}", token))
-                .send_json(req)?
-                .into_json()?;
+                .send_json(req),
+        )?;
         ensure!(resp.code == 0, resp.msg);
         Ok(())
     }
@@ -117,20 +220,23 @@ impl Client {
         message_type: MessageType,
         content: Value,
     ) -> Result<()> {
+        let req = json!({
+            "msg_type": message_type,
+            "content": serde_json::to_string(&content)?,
+            "uuid": uuid::Uuid::new_v4().to_string()
+        });
+        let api = format!("im/v1/messages/{}/reply", &message_id);
+        if let Some(dry_run) = &self.dry_run {
+            return dry_run.record(&api, req);
+        }
+        let _span = request_span("im/v1/messages/reply");
         let token = self.get_tenant_access_token()?;
-        let resp: Resp<SendMessageData> = ureq::post(&format!(
-            "https://open.feishu.cn/open-apis/im/v1/messages/{}/reply",
-            &message_id
-        ))
-        .set("Authorization", &format!("Bearer {}", token))
-        .send_json(json!(
-            {
-                "msg_type": message_type,
-                "content": serde_json::to_string(&content)?,
-                "uuid": uuid::Uuid::new_v4().to_string()
-            }
-        ))?
-        .into_json()?;
+        let resp: Resp<SendMessageData> = call(
+            "im/v1/messages/reply",
+            ureq::post(&format!("https://open.feishu.cn/open-apis/{}", api))
+                .set("Authorization", &format!("Bearer {}", token))
+                .send_json(req),
+        )?;
         ensure!(resp.code == 0, resp.msg);
         Ok(())
     }
@@ -161,30 +267,234 @@ impl Client {
         Ok(())
     }
 
+    /// Where the browser is sent to log in, Feishu redirects back with `code` and `state`.
+    /// `redirect_uri` must be registered in the app's security settings.
+    pub fn authorize_url(&self, redirect_uri: &str, state: &str) -> String {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("app_id", &self.app_id)
+            .append_pair("redirect_uri", redirect_uri)
+            .append_pair("state", state)
+            .finish();
+        format!(
+            "https://open.feishu.cn/open-apis/authen/v1/authorize?{}",
+            query
+        )
+    }
+
+    /// Exchanges the code of a login redirect for the user who logged in.
+    pub fn login_user(&self, code: &str, redirect_uri: &str) -> Result<LoginUser> {
+        #[derive(Deserialize)]
+        struct TokenResp {
+            code: isize,
+            #[serde(default)]
+            error_description: String,
+            #[serde(default)]
+            access_token: String,
+        }
+
+        ensure!(self.dry_run.is_none(), "login is not available in dry run");
+        let _span = request_span("authen/v2/oauth/token");
+        let resp: TokenResp = call(
+            "authen/v2/oauth/token",
+            ureq::post("https://open.feishu.cn/open-apis/authen/v2/oauth/token").send_json(json!({
+                "grant_type": "authorization_code",
+                "client_id": self.app_id,
+                "client_secret": self.app_secret,
+                "code": code,
+                "redirect_uri": redirect_uri,
+            })),
+        )?;
+        ensure!(resp.code == 0, resp.error_description);
+        let resp: Resp<LoginUser> = call(
+            "authen/v1/user_info",
+            ureq::get("https://open.feishu.cn/open-apis/authen/v1/user_info")
+                .set("Authorization", &format!("Bearer {}", resp.access_token))
+                .call(),
+        )?;
+        ensure!(resp.code == 0, resp.msg);
+        Ok(resp.data)
+    }
+
+    /// Open ids of the users in the chat, the bot must be in it.
+    pub fn chat_member_ids(&self, chat_id: &str) -> Result<Vec<String>> {
+        #[derive(Deserialize, Default)]
+        #[serde(default)]
+        struct Member {
+            member_id: String,
+        }
+        #[derive(Deserialize, Default)]
+        #[serde(default)]
+        struct MembersData {
+            items: Vec<Member>,
+            page_token: String,
+            has_more: bool,
+        }
+
+        ensure!(
+            self.dry_run.is_none(),
+            "chat members are not available in dry run"
+        );
+        let _span = request_span("im/v1/chats/members");
+        let token = self.get_tenant_access_token()?;
+        let mut ids = vec![];
+        let mut page_token = String::new();
+        loop {
+            let resp: Resp<MembersData> = call(
+                "im/v1/chats/members",
+                ureq::get(&format!(
+                    "https://open.feishu.cn/open-apis/im/v1/chats/{}/members",
+                    chat_id
+                ))
+                .query("member_id_type", "open_id")
+                .query("page_size", "100")
+                .query("page_token", &page_token)
+                .set("Authorization", &format!("Bearer {}", token))
+                .call(),
+            )?;
+            ensure!(resp.code == 0, resp.msg);
+            ids.extend(resp.data.items.into_iter().map(|m| m.member_id));
+            if !resp.data.has_more || resp.data.page_token.is_empty() {
+                return Ok(ids);
+            }
+            page_token = resp.data.page_token;
+        }
+    }
+
+    /// Names of the chats the bot is in, by chat id.
+    pub fn chat_names(&self) -> Result<HashMap<String, String>> {
+        #[derive(Deserialize, Default)]
+        #[serde(default)]
+        struct Chat {
+            chat_id: String,
+            name: String,
+        }
+        #[derive(Deserialize, Default)]
+        #[serde(default)]
+        struct ChatsData {
+            items: Vec<Chat>,
+            page_token: String,
+            has_more: bool,
+        }
+
+        ensure!(
+            self.dry_run.is_none(),
+            "chat names are not available in dry run"
+        );
+        let _span = request_span("im/v1/chats");
+        let token = self.get_tenant_access_token()?;
+        let mut names = HashMap::new();
+        let mut page_token = String::new();
+        loop {
+            let resp: Resp<ChatsData> = call(
+                "im/v1/chats",
+                ureq::get("https://open.feishu.cn/open-apis/im/v1/chats")
+                    .query("page_size", "100")
+                    .query("page_token", &page_token)
+                    .set("Authorization", &format!("Bearer {}", token))
+                    .call(),
+            )?;
+            ensure!(resp.code == 0, resp.msg);
+            names.extend(resp.data.items.into_iter().map(|c| (c.chat_id, c.name)));
+            if !resp.data.has_more || resp.data.page_token.is_empty() {
+                return Ok(names);
+            }
+            page_token = resp.data.page_token;
+        }
+    }
+
+    /// Returns the cached token, or requests a new one when it is about to expire.
     pub fn get_tenant_access_token(&self) -> Result<String> {
+        let mut cached = self.token.lock().unwrap();
+        if let Some((token, refresh_at)) = &*cached {
+            if Instant::now() < *refresh_at {
+                return Ok(token.clone());
+            }
+        }
+        let (token, expire) = self.request_tenant_access_token()?;
+        logging::add_secret(&token);
+        let ttl = Duration::from_secs(expire).saturating_sub(TOKEN_EXPIRY_MARGIN);
+        *cached = Some((token.clone(), Instant::now() + ttl));
+        Ok(token)
+    }
+
+    /// Returns the token and its lifetime in seconds.
+    fn request_tenant_access_token(&self) -> Result<(String, u64)> {
         #[derive(Serialize, Deserialize)]
         struct Resp {
             code: isize,
             msg: String,
             tenant_access_token: String,
-            expire: usize,
+            expire: u64,
         }
 
-        let resp: Resp =
+        let _span = request_span("auth/v3/tenant_access_token");
+        let sent =
             ureq::post("https://open.feishu.cn/open-apis/auth/v3/tenant_access_token/internal/")
                 .send_json(ureq::json! ({
                     "app_id": self.app_id,
                     "app_secret": self.app_secret
-                }))?
-                .into_json()?;
-        ensure!(resp.code == 0, resp.msg);
-        Ok(resp.tenant_access_token)
+                }));
+        let ret = call("auth/v3/tenant_access_token", sent).and_then(|resp: Resp| {
+            ensure!(resp.code == 0, resp.msg);
+            Ok((resp.tenant_access_token, resp.expire))
+        });
+        let result = if ret.is_ok() { "ok" } else { "error" };
+        metrics::TOKEN_REFRESHES.with_label_values(&[result]).inc();
+        ret
+    }
+}
+
+/// Sends a request and counts it by endpoint and Feishu result code.
+fn call<T: DeserializeOwned>(
+    endpoint: &str,
+    sent: Result<ureq::Response, ureq::Error>,
+) -> Result<T> {
+    let (code, ret): (String, Result<Value>) = match sent {
+        Ok(resp) => match resp.into_json::<Value>() {
+            Ok(body) => (body["code"].to_string(), Ok(body)),
+            Err(e) => ("error".to_string(), Err(e.into())),
+        },
+        Err(ureq::Error::Status(status, resp)) => (
+            format!("http_{}", status),
+            Err(ureq::Error::Status(status, resp).into()),
+        ),
+        Err(e) => ("error".to_string(), Err(e.into())),
+    };
+    metrics::FEISHU_REQUESTS
+        .with_label_values(&[endpoint, &code])
+        .inc();
+    telemetry::set_attribute(KeyValue::new("feishu.code", code.clone()));
+    if code != "0" {
+        telemetry::set_error(format!("feishu returned {}", code));
     }
+    Ok(serde_json::from_value(ret?)?)
+}
+
+fn request_span(endpoint: &'static str) -> ContextGuard {
+    telemetry::span(
+        "feishu.request",
+        vec![KeyValue::new("feishu.endpoint", endpoint)],
+    )
+}
+
+/// Body of the send message API, the content is itself a JSON string.
+pub fn message_request(
+    chat_id: String,
+    message_type: MessageType,
+    content: &Value,
+) -> Result<Value> {
+    Ok(json!({
+        "receive_id": chat_id,
+        "msg_type": message_type,
+        "content": serde_json::to_string(content)?,
+        "uuid": uuid::Uuid::new_v4().to_string()
+    }))
 }
 
 #[cfg(test)]
 mod tests {
-    use ureq::json;
+    use super::*;
+    use std::io::Read;
 
     #[ignore]
     #[test]
@@ -199,11 +509,48 @@ mod tests {
         // get app_id and app_secret from environment
         let app_id = std::env::var("APP_ID").unwrap();
         let app_secret = std::env::var("APP_SECRET").unwrap();
-        let client = super::Client::new(app_id, app_secret);
+        let client = Client::new(app_id, app_secret);
         // read bytes from file
         let file_name = "test.py";
         let data = std::fs::read(file_name).unwrap();
-        let ret = client.create_file(super::FileType::Stream, "test.py".to_string(), &data);
+        let ret = client.create_file(FileType::Stream, "test.py".to_string(), &data);
         assert!(ret.is_ok());
     }
+
+    #[test]
+    fn test_dry_run() {
+        let mut out = tempfile::NamedTempFile::new().unwrap();
+        let file = out.reopen().unwrap();
+        let client = Client::new("id".to_string(), "secret".to_string())
+            .with_dry_run(Some(DryRun::File(Arc::new(Mutex::new(file)))));
+        let file_key = client
+            .create_file(FileType::Stream, "a.txt".to_string(), b"hello")
+            .unwrap();
+        assert!(file_key.starts_with("dry-run-"));
+        client
+            .send_text_message("oc_1".to_string(), "hi".to_string())
+            .unwrap();
+        client
+            .reply_message("om_1".to_string(), MessageType::Text, json!({"text": "re"}))
+            .unwrap();
+
+        let mut lines = String::new();
+        out.read_to_string(&mut lines).unwrap();
+        let lines: Vec<Value> = lines
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["api"], "im/v1/files");
+        assert_eq!(lines[0]["body"]["size"], 5);
+        assert_eq!(lines[1]["api"], "im/v1/messages");
+        assert_eq!(lines[1]["body"]["receive_id"], "oc_1");
+        assert_eq!(lines[1]["body"]["content"], r#"{"text":"hi"}"#);
+        assert_eq!(lines[2]["api"], "im/v1/messages/om_1/reply");
+        assert_eq!(
+            summary(&lines[1]["body"])["content"],
+            json!({ "bytes": 13 })
+        );
+        assert_eq!(summary(&lines[1]["body"])["receive_id"], "oc_1");
+    }
 }
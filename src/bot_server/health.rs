@@ -0,0 +1,114 @@
+use crate::bot_server::feishu_client::Client;
+use crate::listen;
+use crate::shutdown::Shutdown;
+use crate::store::Store;
+use actix_web::web::{self, Data};
+use actix_web::HttpResponse;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+
+/// Listeners that take mail, any of them counts as the SMTP side being up.
+const MAIL_LISTENERS: &[&str] = &["smtp", "smtps", "lmtp"];
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    detail: String,
+}
+
+impl Check {
+    fn new(ret: Result<String, String>) -> Self {
+        match ret {
+            Ok(detail) => Check { ok: true, detail },
+            Err(detail) => Check { ok: false, detail },
+        }
+    }
+}
+
+/// The process is alive and serving HTTP.
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+/// Fails with 503 and the failing checks while the instance can't take mail.
+pub async fn readyz(
+    store: Data<Store>,
+    client: Data<Client>,
+    shutdown: Data<Shutdown>,
+) -> HttpResponse {
+    let mut checks = BTreeMap::new();
+    checks.insert("smtp", Check::new(mail_listeners(&listen::accepting())));
+    checks.insert(
+        "store",
+        Check::new(
+            store
+                .check_writable()
+                .map(|_| "writable".to_string())
+                .map_err(|e| e.to_string()),
+        ),
+    );
+    checks.insert("feishu", Check::new(feishu(client.get_ref().clone()).await));
+    if shutdown.is_stopping() {
+        checks.insert("shutdown", Check::new(Err("shutting down".to_string())));
+    }
+    let ready = checks.values().all(|c| c.ok);
+    let body = json!({
+        "status": if ready { "ok" } else { "unavailable" },
+        "checks": checks,
+    });
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+fn mail_listeners(accepting: &HashMap<String, usize>) -> Result<String, String> {
+    let mut running = vec![];
+    for name in MAIL_LISTENERS {
+        match accepting.get(*name) {
+            Some(0) => return Err(format!("{} stopped accepting", name)),
+            Some(_) => running.push(*name),
+            None => {}
+        }
+    }
+    if running.is_empty() {
+        return Err("no listener accepting yet".to_string());
+    }
+    Ok(format!("accepting on {}", running.join(", ")))
+}
+
+async fn feishu(client: Client) -> Result<String, String> {
+    if client.is_dry_run() {
+        return Ok("dry run".to_string());
+    }
+    match web::block(move || client.get_tenant_access_token()).await {
+        Ok(Ok(_)) => Ok("token ok".to_string()),
+        Ok(Err(e)) => Err(format!("get token error: {}", e)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mail_listeners() {
+        let mut accepting = HashMap::new();
+        assert!(mail_listeners(&accepting).is_err());
+        accepting.insert("http".to_string(), 1);
+        accepting.insert("smtp".to_string(), 2);
+        accepting.insert("lmtp".to_string(), 1);
+        assert_eq!(
+            mail_listeners(&accepting).unwrap(),
+            "accepting on smtp, lmtp"
+        );
+        accepting.insert("lmtp".to_string(), 0);
+        assert_eq!(
+            mail_listeners(&accepting).unwrap_err(),
+            "lmtp stopped accepting"
+        );
+    }
+}
@@ -0,0 +1,399 @@
+use crate::bot_server::feishu_client::Client;
+use crate::store::Store;
+use crate::{config, logging};
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::error::{ErrorBadRequest, ErrorForbidden, ErrorInternalServerError, InternalError};
+use actix_web::http::header::LOCATION;
+use actix_web::web::{self, Data};
+use actix_web::{HttpRequest, HttpResponse};
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SESSION_COOKIE: &str = "mailhook_session";
+/// Holds the OAuth state and the page to return to while the user is at Feishu.
+const LOGIN_COOKIE: &str = "mailhook_login";
+const SESSION_TTL: Duration = Duration::from_secs(7 * 24 * 3600);
+const LOGIN_TTL: Duration = Duration::from_secs(600);
+/// How long a chat's member list is trusted before asking Feishu again.
+const MEMBERS_TTL: Duration = Duration::from_secs(300);
+
+/// Member open ids of each chat and when they were fetched.
+type MemberCache = HashMap<String, (HashSet<String>, Instant)>;
+
+/// Feishu login in front of the mail pages. A signed link only opens for a
+/// logged in member of one of the chats the mail was delivered to.
+#[derive(Clone)]
+pub struct WebLogin {
+    base_url: String,
+    key: Vec<u8>,
+    /// Open ids allowed on the admin pages.
+    admins: Vec<String>,
+    members: Arc<Mutex<MemberCache>>,
+}
+
+impl WebLogin {
+    pub fn new(base_url: String, key: &[u8], admins: Vec<String>) -> Self {
+        WebLogin {
+            base_url,
+            key: key.to_vec(),
+            admins,
+            members: Arc::default(),
+        }
+    }
+
+    /// Enabled by `WEB_LOGIN=true`, sessions are signed with a key kept in the store.
+    /// `WEB_ADMINS` is a comma separated list of open ids.
+    pub fn from_env(base_url: String, store: &Store) -> Result<Option<Self>> {
+        Self::check_env()?;
+        if !login_enabled() {
+            return Ok(None);
+        }
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let key = store.session_key(&hex::encode(secret))?;
+        logging::add_secret(&key);
+        Ok(Some(WebLogin::new(base_url, key.as_bytes(), admins())))
+    }
+
+    /// Validates the login settings without touching the store.
+    pub fn check_env() -> Result<()> {
+        if !admins().is_empty() && !login_enabled() {
+            return Err(anyhow!("`WEB_ADMINS` needs `WEB_LOGIN=true`"));
+        }
+        Ok(())
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn redirect_uri(&self) -> String {
+        format!("{}/auth/callback", self.base_url)
+    }
+
+    fn cookie<'c>(&self, name: &'c str, value: String, ttl: Duration) -> Cookie<'c> {
+        Cookie::build(name, value)
+            .path("/")
+            .http_only(true)
+            .secure(self.base_url.starts_with("https://"))
+            // Lax still sends it on the redirect back from Feishu
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::seconds(ttl.as_secs() as i64))
+            .finish()
+    }
+
+    fn session_value(&self, open_id: &str, exp: u64) -> String {
+        let sig = self.sign(open_id, exp).finalize().into_bytes();
+        format!("{}.{}.{}", open_id, exp, hex::encode(sig))
+    }
+
+    /// The open id of a valid session cookie value.
+    fn check_session(&self, value: &str, now: u64) -> Option<String> {
+        let mut parts = value.rsplitn(3, '.');
+        let sig = hex::decode(parts.next()?).ok()?;
+        let exp: u64 = parts.next()?.parse().ok()?;
+        let open_id = parts.next()?;
+        self.sign(open_id, exp).verify_slice(&sig).ok()?;
+        (exp >= now).then(|| open_id.to_string())
+    }
+
+    fn sign(&self, open_id: &str, exp: u64) -> Hmac<Sha256> {
+        self.mac(&format!("session\n{}\n{}", open_id, exp))
+    }
+
+    fn mac(&self, message: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("any key size");
+        mac.update(message.as_bytes());
+        mac
+    }
+
+    fn is_member(&self, client: &Client, chat_id: &str, open_id: &str) -> Result<bool> {
+        if let Some((members, fetched_at)) = self.members.lock().unwrap().get(chat_id) {
+            if fetched_at.elapsed() < MEMBERS_TTL {
+                return Ok(members.contains(open_id));
+            }
+        }
+        let members: HashSet<String> = client.chat_member_ids(chat_id)?.into_iter().collect();
+        let found = members.contains(open_id);
+        self.members
+            .lock()
+            .unwrap()
+            .insert(chat_id.to_string(), (members, Instant::now()));
+        Ok(found)
+    }
+
+    /// The open id of the logged in user, visitors without a session are sent to the login page.
+    pub fn user(&self, req: &HttpRequest) -> actix_web::Result<String> {
+        let open_id = req
+            .cookie(SESSION_COOKIE)
+            .and_then(|c| self.check_session(c.value(), now()));
+        let Some(open_id) = open_id else {
+            let next = req.uri().path_and_query().map_or("/", |p| p.as_str());
+            let login_url = format!(
+                "{}/auth/login?{}",
+                self.base_url,
+                form_urlencoded::Serializer::new(String::new())
+                    .append_pair("next", next)
+                    .finish()
+            );
+            return Err(
+                InternalError::from_response("login required", redirect(&login_url)).into(),
+            );
+        };
+        Ok(open_id)
+    }
+
+    pub fn admin(&self, req: &HttpRequest) -> actix_web::Result<String> {
+        let open_id = self.user(req)?;
+        if !self.admins.contains(&open_id) {
+            info!("web login: {} is not an admin", open_id);
+            return Err(ErrorForbidden("admins only"));
+        }
+        Ok(open_id)
+    }
+
+    /// Token the admin forms post back, tied to the user so it can't be forged
+    /// by another site.
+    pub fn csrf_token(&self, open_id: &str) -> String {
+        let mac = self.mac(&format!("csrf\n{}", open_id));
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    pub fn check_csrf(&self, open_id: &str, token: &str) -> bool {
+        let mac = self.mac(&format!("csrf\n{}", open_id));
+        hex::decode(token).is_ok_and(|token| mac.verify_slice(&token).is_ok())
+    }
+
+    /// Sends visitors without a session to the login page, and refuses users
+    /// who are in none of the mail's chats.
+    pub async fn authorize(
+        &self,
+        req: &HttpRequest,
+        store: &Store,
+        client: &Client,
+        mail_id: &str,
+    ) -> actix_web::Result<()> {
+        let open_id = self.user(req)?;
+        let chats = store
+            .mail_chats(mail_id)
+            .map_err(ErrorInternalServerError)?;
+        let login = self.clone();
+        let client = client.clone();
+        let user = open_id.clone();
+        let allowed = web::block(move || -> Result<bool> {
+            for chat_id in chats {
+                if login.is_member(&client, &chat_id, &user)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        })
+        .await
+        .map_err(ErrorInternalServerError)?
+        .map_err(|e| ErrorInternalServerError(format!("check chat members error: {}", e)))?;
+        if !allowed {
+            info!("web login: {} refused mail {}", open_id, mail_id);
+            return Err(ErrorForbidden(
+                "you are not a member of the chats this mail was sent to",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn redirect(url: &str) -> HttpResponse {
+    HttpResponse::Found()
+        .insert_header((LOCATION, url))
+        .finish()
+}
+
+/// Only paths on this site, `//host` would leave it.
+fn safe_next(next: &str) -> &str {
+    if next.starts_with('/') && !next.starts_with("//") && !next.starts_with("/\\") {
+        next
+    } else {
+        "/"
+    }
+}
+
+fn login_enabled() -> bool {
+    matches!(config::var("WEB_LOGIN").as_deref(), Ok("true") | Ok("1"))
+}
+
+fn admins() -> Vec<String> {
+    config::var("WEB_ADMINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn enabled(login: &Option<WebLogin>) -> actix_web::Result<&WebLogin> {
+    login
+        .as_ref()
+        .ok_or_else(|| actix_web::error::ErrorNotFound("login is not enabled"))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct LoginQuery {
+    next: String,
+}
+
+pub async fn login(
+    query: web::Query<LoginQuery>,
+    login: Data<Option<WebLogin>>,
+    client: Data<Client>,
+) -> actix_web::Result<HttpResponse> {
+    let login = enabled(&login)?;
+    let mut nonce = [0u8; 16];
+    OsRng.fill_bytes(&mut nonce);
+    let state = hex::encode(nonce);
+    let next: String = form_urlencoded::byte_serialize(safe_next(&query.next).as_bytes()).collect();
+    let cookie = login.cookie(LOGIN_COOKIE, format!("{}:{}", state, next), LOGIN_TTL);
+    Ok(HttpResponse::Found()
+        .cookie(cookie)
+        .insert_header((
+            LOCATION,
+            client.authorize_url(&login.redirect_uri(), &state),
+        ))
+        .finish())
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct CallbackQuery {
+    code: String,
+    state: String,
+}
+
+pub async fn callback(
+    req: HttpRequest,
+    query: web::Query<CallbackQuery>,
+    login: Data<Option<WebLogin>>,
+    client: Data<Client>,
+) -> actix_web::Result<HttpResponse> {
+    let login = enabled(&login)?;
+    let cookie = req
+        .cookie(LOGIN_COOKIE)
+        .ok_or_else(|| ErrorBadRequest("login expired, open the mail link again"))?;
+    let (state, next) = cookie
+        .value()
+        .split_once(':')
+        .ok_or_else(|| ErrorBadRequest("invalid login state"))?;
+    if query.state.is_empty() || state != query.state {
+        return Err(ErrorBadRequest("invalid login state"));
+    }
+    if query.code.is_empty() {
+        return Err(ErrorForbidden("login cancelled"));
+    }
+    let next = form_urlencoded::parse(format!("n={}", next).as_bytes())
+        .next()
+        .map(|(_, next)| safe_next(&next).to_string())
+        .unwrap_or_else(|| "/".to_string());
+    let code = query.code.clone();
+    let redirect_uri = login.redirect_uri();
+    let client = (*client).clone();
+    let user = web::block(move || client.login_user(&code, &redirect_uri))
+        .await
+        .map_err(ErrorInternalServerError)?
+        .map_err(|e| {
+            warn!("web login error: {}", e);
+            ErrorForbidden("login failed")
+        })?;
+    info!("web login: {} ({})", user.name, user.open_id);
+    let session = login.session_value(&user.open_id, now().saturating_add(SESSION_TTL.as_secs()));
+    let mut done = login.cookie(LOGIN_COOKIE, String::new(), Duration::ZERO);
+    done.make_removal();
+    Ok(HttpResponse::Found()
+        .cookie(login.cookie(SESSION_COOKIE, session, SESSION_TTL))
+        .cookie(done)
+        .insert_header((LOCATION, format!("{}{}", login.base_url, next)))
+        .finish())
+}
+
+pub async fn logout(login: Data<Option<WebLogin>>) -> actix_web::Result<HttpResponse> {
+    let login = enabled(&login)?;
+    let mut cookie = login.cookie(SESSION_COOKIE, String::new(), Duration::ZERO);
+    cookie.make_removal();
+    Ok(HttpResponse::Ok().cookie(cookie).body("logged out"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_session() {
+        let login = WebLogin::new("https://web.test".to_string(), b"0123456789abcdef", vec![]);
+        let value = login.session_value("ou_1", 100);
+        assert_eq!(login.check_session(&value, 100).as_deref(), Some("ou_1"));
+        assert_eq!(login.check_session(&value, 101), None);
+        let forged = value.replacen("ou_1", "ou_2", 1);
+        assert_eq!(login.check_session(&forged, 100), None);
+        let other = WebLogin::new("https://web.test".to_string(), b"fedcba9876543210", vec![]);
+        assert_eq!(other.check_session(&value, 100), None);
+        assert_eq!(login.check_session("garbage", 0), None);
+    }
+
+    #[test]
+    fn test_safe_next() {
+        assert_eq!(safe_next("/mail/1?exp=1"), "/mail/1?exp=1");
+        assert_eq!(safe_next("//evil.test/"), "/");
+        assert_eq!(safe_next("/\\evil.test/"), "/");
+        assert_eq!(safe_next("https://evil.test/"), "/");
+    }
+
+    #[actix_web::test]
+    async fn test_authorize_redirects_to_login() {
+        let login = WebLogin::new(
+            "https://web.test/hook".to_string(),
+            b"0123456789abcdef",
+            vec![],
+        );
+        let store = Store::in_memory().unwrap();
+        let client = Client::new("app".to_string(), "secret".to_string());
+        let req = TestRequest::get()
+            .uri("/mail/m1?exp=1&kid=k&sig=00")
+            .to_http_request();
+        let e = login
+            .authorize(&req, &store, &client, "m1")
+            .await
+            .unwrap_err();
+        let resp = e.error_response();
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(
+            resp.headers().get(LOCATION).unwrap(),
+            "https://web.test/hook/auth/login?next=%2Fmail%2Fm1%3Fexp%3D1%26kid%3Dk%26sig%3D00"
+        );
+
+        // a session of a user in none of the chats, a mail without chats asks nobody
+        let session = login.session_value("ou_1", now() + 60);
+        let req = TestRequest::get()
+            .cookie(Cookie::new(SESSION_COOKIE, session))
+            .to_http_request();
+        let e = login
+            .authorize(&req, &store, &client, "m1")
+            .await
+            .unwrap_err();
+        assert_eq!(e.error_response().status(), StatusCode::FORBIDDEN);
+    }
+}
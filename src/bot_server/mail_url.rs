@@ -0,0 +1,268 @@
+use crate::store::Store;
+use crate::{config, logging};
+use anyhow::{anyhow, Context, Result};
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_TTL: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// Key id used for the key generated into the store when none is configured.
+const STORE_KEY_ID: &str = "store";
+
+#[derive(Clone)]
+pub struct LinkKey {
+    id: String,
+    secret: Vec<u8>,
+}
+
+impl LinkKey {
+    pub fn new(id: &str, secret: &[u8]) -> Result<Self> {
+        if id.is_empty()
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(anyhow!("invalid key id `{}`", id));
+        }
+        if secret.len() < 16 {
+            return Err(anyhow!("key `{}` must be at least 16 bytes", id));
+        }
+        Ok(LinkKey {
+            id: id.to_string(),
+            secret: secret.to_vec(),
+        })
+    }
+}
+
+/// Query of a signed mail link.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct LinkQuery {
+    pub exp: u64,
+    pub kid: String,
+    pub sig: String,
+}
+
+impl LinkQuery {
+    /// The signed part of the query, for links to the parts of a mail.
+    pub fn to_query(&self) -> String {
+        format!("exp={}&kid={}&sig={}", self.exp, self.kid, self.sig)
+    }
+}
+
+/// Signs mail links with HMAC-SHA256. Links expire after `ttl`, every key
+/// verifies but only the first one signs, so keys can be rotated.
+#[derive(Clone)]
+pub struct MailUrlGen {
+    base_url: String,
+    keys: Vec<LinkKey>,
+    ttl: Duration,
+}
+
+impl MailUrlGen {
+    pub fn new(base_url: String, keys: Vec<LinkKey>, ttl: Duration) -> Result<Self> {
+        if keys.is_empty() {
+            return Err(anyhow!("no mail link key"));
+        }
+        Ok(MailUrlGen {
+            base_url,
+            keys,
+            ttl,
+        })
+    }
+
+    /// `MAIL_LINK_KEYS` is a comma separated list of `id:secret`, without it a
+    /// random key is generated once and kept in the store.
+    pub fn from_env(base_url: String, store: &Store) -> Result<Self> {
+        let keys = match config::var("MAIL_LINK_KEYS") {
+            Ok(keys) if !keys.is_empty() => parse_keys(&keys).context("MAIL_LINK_KEYS")?,
+            _ => {
+                let mut secret = [0u8; 32];
+                OsRng.fill_bytes(&mut secret);
+                let secret = store.link_key(&hex::encode(secret))?;
+                logging::add_secret(&secret);
+                vec![LinkKey::new(STORE_KEY_ID, secret.as_bytes())?]
+            }
+        };
+        MailUrlGen::new(base_url, keys, ttl_from_env()?)
+    }
+
+    /// Validates the link settings without touching the store.
+    pub fn check_env() -> Result<()> {
+        if let Ok(keys) = config::var("MAIL_LINK_KEYS") {
+            if !keys.is_empty() {
+                parse_keys(&keys).context("MAIL_LINK_KEYS")?;
+            }
+        }
+        ttl_from_env()?;
+        Ok(())
+    }
+
+    pub fn gen_url(&self, id: &str) -> String {
+        self.gen_url_at(id, now())
+    }
+
+    fn gen_url_at(&self, id: &str, now: u64) -> String {
+        let key = &self.keys[0];
+        let exp = now.saturating_add(self.ttl.as_secs());
+        let query = LinkQuery {
+            exp,
+            kid: key.id.clone(),
+            sig: hex::encode(sign(key, id, exp).finalize().into_bytes()),
+        };
+        format!("{}/mail/{}?{}", &self.base_url, id, query.to_query())
+    }
+
+    /// Returns why the link is refused, revocation is checked against the store by the caller.
+    pub fn check(&self, id: &str, query: &LinkQuery) -> Result<(), &'static str> {
+        self.check_at(id, query, now())
+    }
+
+    fn check_at(&self, id: &str, query: &LinkQuery, now: u64) -> Result<(), &'static str> {
+        let key = self
+            .keys
+            .iter()
+            .find(|k| k.id == query.kid)
+            .ok_or("invalid link")?;
+        let sig = hex::decode(&query.sig).map_err(|_| "invalid link")?;
+        // verify_slice compares in constant time
+        sign(key, id, query.exp)
+            .verify_slice(&sig)
+            .map_err(|_| "invalid link")?;
+        if query.exp < now {
+            return Err("link expired");
+        }
+        Ok(())
+    }
+}
+
+/// Link to the `n`th attachment, signed like the mail link it is derived from.
+pub fn attachment_url(mail_url: &str, n: usize) -> String {
+    match mail_url.split_once('?') {
+        Some((path, query)) => format!("{}/attachments/{}?{}", path, n, query),
+        None => format!("{}/attachments/{}", mail_url, n),
+    }
+}
+
+fn ttl_from_env() -> Result<Duration> {
+    match config::var("MAIL_LINK_TTL") {
+        Ok(ttl) => Ok(Duration::from_secs(ttl.parse().context("MAIL_LINK_TTL")?)),
+        Err(_) => Ok(DEFAULT_TTL),
+    }
+}
+
+fn parse_keys(s: &str) -> Result<Vec<LinkKey>> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|entry| {
+            let (id, secret) = entry
+                .split_once(':')
+                .ok_or_else(|| anyhow!("expected `id:secret`"))?;
+            logging::add_secret(secret);
+            LinkKey::new(id, secret.as_bytes())
+        })
+        .collect()
+}
+
+fn sign(key: &LinkKey, id: &str, exp: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key.secret).expect("any key size");
+    mac.update(format!("{}\n{}\n{}", key.id, id, exp).as_bytes());
+    mac
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(url: &str) -> LinkQuery {
+        let query = url.split_once('?').unwrap().1;
+        actix_web::web::Query::<LinkQuery>::from_query(query)
+            .unwrap()
+            .into_inner()
+    }
+
+    #[test]
+    fn test_huge_ttl_saturates() {
+        let key = LinkKey::new("k1", b"0123456789abcdef").unwrap();
+        let gen = MailUrlGen::new(
+            "https://web.test/mailhook".to_string(),
+            vec![key],
+            Duration::from_secs(u64::MAX),
+        )
+        .unwrap();
+        let q = query(&gen.gen_url_at("mail1", 1000));
+        assert_eq!(q.exp, u64::MAX);
+        assert_eq!(gen.check_at("mail1", &q, 1000), Ok(()));
+    }
+
+    #[test]
+    fn test_sign_and_check() {
+        let old = LinkKey::new("k1", b"0123456789abcdef").unwrap();
+        let new = LinkKey::new("k2", b"fedcba9876543210").unwrap();
+        let ttl = Duration::from_secs(100);
+        let gen = MailUrlGen::new(
+            "https://web.test/mailhook".to_string(),
+            vec![old.clone()],
+            ttl,
+        )
+        .unwrap();
+        let url = gen.gen_url_at("mail1", 1000);
+        assert!(url.starts_with("https://web.test/mailhook/mail/mail1?exp=1100&kid=k1&sig="));
+        let q = query(&url);
+        assert_eq!(gen.check_at("mail1", &q, 1050), Ok(()));
+        assert_eq!(gen.check_at("mail1", &q, 1101), Err("link expired"));
+        assert_eq!(gen.check_at("mail2", &q, 1050), Err("invalid link"));
+        let forged = LinkQuery { exp: 9999, ..q };
+        assert_eq!(gen.check_at("mail1", &forged, 1050), Err("invalid link"));
+
+        // rotated: new links use k2, links signed with k1 still work until removed
+        let rotated = MailUrlGen::new(
+            "https://web.test/mailhook".to_string(),
+            vec![new.clone(), old],
+            ttl,
+        )
+        .unwrap();
+        assert!(rotated.gen_url_at("mail1", 1000).contains("kid=k2"));
+        assert_eq!(rotated.check_at("mail1", &query(&url), 1050), Ok(()));
+        let removed =
+            MailUrlGen::new("https://web.test/mailhook".to_string(), vec![new], ttl).unwrap();
+        assert_eq!(
+            removed.check_at("mail1", &query(&url), 1050),
+            Err("invalid link")
+        );
+        assert_eq!(
+            removed.check_at("mail1", &LinkQuery::default(), 1050),
+            Err("invalid link")
+        );
+    }
+
+    #[test]
+    fn test_attachment_url() {
+        let key = LinkKey::new("k1", b"0123456789abcdef").unwrap();
+        let gen = MailUrlGen::new("https://web.test".to_string(), vec![key], DEFAULT_TTL).unwrap();
+        let url = attachment_url(&gen.gen_url_at("mail1", 1000), 2);
+        assert!(url.starts_with("https://web.test/mail/mail1/attachments/2?exp="));
+        assert_eq!(gen.check_at("mail1", &query(&url), 1050), Ok(()));
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        let keys = parse_keys("k2:fedcba9876543210, k1:0123456789abcdef").unwrap();
+        assert_eq!(keys[0].id, "k2");
+        assert_eq!(keys[1].secret, b"0123456789abcdef");
+        assert!(parse_keys("k1:short").is_err());
+        assert!(parse_keys("0123456789abcdef").is_err());
+        assert!(parse_keys("k/1:0123456789abcdef").is_err());
+    }
+}
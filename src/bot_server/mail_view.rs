@@ -0,0 +1,256 @@
+use crate::smtp_server::mail::{MailAttachment, ParsedMail};
+use actix_web::http::header::{
+    Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue,
+};
+use actix_web::mime::{Mime, APPLICATION_OCTET_STREAM};
+use actix_web::HttpResponse;
+use ammonia::{Builder, UrlRelative};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:0 auto;max-width:60em;padding:1em;word-wrap:break-word}\
+th{text-align:left;padding-right:1em;vertical-align:top;color:#666}\
+.notice{background:#fff8e1;padding:.5em}.body{border-top:1px solid #ddd;margin-top:1em;padding-top:1em}\
+pre{white-space:pre-wrap}img{max-width:100%}";
+
+/// The page only needs its own inline style, remote images are let through on request.
+pub fn content_security_policy(remote_images: bool) -> &'static str {
+    if remote_images {
+        "default-src 'none'; style-src 'unsafe-inline'; img-src http: https:"
+    } else {
+        "default-src 'none'; style-src 'unsafe-inline'"
+    }
+}
+
+/// Renders the page of `/mail/{id}`. Links are relative to it and carry the
+/// signed `query`, so they keep working behind a path prefix.
+pub fn render(id: &str, mail: &ParsedMail, query: &str, remote_images: bool) -> String {
+    let id = escape(id);
+    let query = escape(query);
+    let subject = match mail.subject.trim() {
+        "" => "(no subject)".to_string(),
+        subject => escape(subject),
+    };
+    let mut page = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>{}</title><style>{}</style></head><body><h2>{}</h2><table>",
+        subject, STYLE, subject
+    );
+    for (name, value) in [
+        ("From", &mail.from),
+        ("To", &mail.to),
+        ("Cc", &mail.cc),
+        ("Date", &mail.date),
+    ] {
+        if !value.is_empty() {
+            page.push_str(&format!(
+                "<tr><th>{}</th><td>{}</td></tr>",
+                name,
+                escape(value)
+            ));
+        }
+    }
+    page.push_str("</table>");
+    if !mail.attachments.is_empty() {
+        page.push_str("<ul>");
+        for (n, attachment) in mail.attachments.iter().enumerate() {
+            page.push_str(&format!(
+                "<li><a href=\"{}/attachments/{}?{}\">{}</a> ({}, {})</li>",
+                id,
+                n,
+                query,
+                escape(&attachment.filename),
+                escape(&attachment.mime_type),
+                human_size(attachment.data.len())
+            ));
+        }
+        page.push_str("</ul>");
+    }
+    page.push_str(&format!(
+        "<p><a href=\"{}/raw?{}\">Download .eml</a></p>",
+        id, query
+    ));
+    match &mail.html {
+        Some(html) => {
+            let (html, blocked) = sanitize(html, remote_images);
+            if blocked {
+                page.push_str(&format!(
+                    "<p class=\"notice\">Remote images are blocked. \
+                     <a href=\"{}?{}&amp;images=true\">Show images</a></p>",
+                    id, query
+                ));
+            }
+            page.push_str(&format!("<div class=\"body\">{}</div>", html));
+            if !mail.text.trim().is_empty() {
+                page.push_str(&format!(
+                    "<details><summary>Plain text</summary><pre>{}</pre></details>",
+                    escape(&mail.text)
+                ));
+            }
+        }
+        None => page.push_str(&format!("<pre class=\"body\">{}</pre>", escape(&mail.text))),
+    }
+    page.push_str("</body></html>");
+    page
+}
+
+/// Strips scripts, styles and event handlers. Returns whether a remote image was dropped.
+pub fn sanitize(html: &str, remote_images: bool) -> (String, bool) {
+    let blocked = Arc::new(AtomicBool::new(false));
+    let blocked_clone = blocked.clone();
+    let html = Builder::default()
+        // relative urls would point into this server
+        .url_relative(UrlRelative::Deny)
+        .set_tag_attribute_value("a", "target", "_blank")
+        .attribute_filter(move |element, attribute, value| {
+            if element == "img" && attribute == "src" && !remote_images && is_remote(value) {
+                blocked_clone.store(true, Ordering::Relaxed);
+                return None;
+            }
+            Some(value.into())
+        })
+        .clean(html)
+        .to_string();
+    (html, blocked.load(Ordering::Relaxed))
+}
+
+/// Always a download, with an ASCII fallback name for old clients.
+fn content_disposition(filename: &str) -> ContentDisposition {
+    let fallback = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let mut parameters = vec![DispositionParam::Filename(fallback)];
+    if !filename.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_string()),
+            language_tag: None,
+            value: filename.as_bytes().to_vec(),
+        }));
+    }
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters,
+    }
+}
+
+/// The declared type of an attachment, `application/octet-stream` if it doesn't parse.
+fn content_type(mime_type: &str) -> Mime {
+    mime_type.parse().unwrap_or(APPLICATION_OCTET_STREAM)
+}
+
+/// Downloads an attachment, for the web view and the API alike.
+pub fn attachment_response(attachment: MailAttachment) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(content_type(&attachment.mime_type))
+        .insert_header(content_disposition(&attachment.filename))
+        // never let the browser render a html attachment as part of this site
+        .append_header(("X-Content-Type-Options", "nosniff"))
+        .append_header(("Content-Security-Policy", "sandbox"))
+        .body(attachment.data)
+}
+
+fn is_remote(url: &str) -> bool {
+    let url = url.trim_start().to_ascii_lowercase();
+    url.starts_with("http:") || url.starts_with("https:") || url.starts_with("//")
+}
+
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn human_size(size: usize) -> String {
+    match size {
+        s if s < 1024 => format!("{} B", s),
+        s if s < 1024 * 1024 => format!("{:.1} KB", s as f64 / 1024.0),
+        s => format!("{:.1} MB", s as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize() {
+        let html = r#"<p onclick="x()">hi<script>alert(1)</script></p><style>p{}</style><img src="https://t.example/p.gif"><a href="/admin">a</a>"#;
+        let (clean, blocked) = sanitize(html, false);
+        assert!(blocked);
+        assert!(!clean.contains("script") && !clean.contains("onclick"));
+        assert!(!clean.contains("p{}") && !clean.contains("t.example"));
+        assert!(!clean.contains("/admin"));
+        let (clean, blocked) = sanitize(html, true);
+        assert!(!blocked);
+        assert!(clean.contains(r#"src="https://t.example/p.gif""#));
+    }
+
+    #[test]
+    fn test_attachment_headers() {
+        assert_eq!(
+            content_disposition("a\"b.pdf").to_string(),
+            r#"attachment; filename="a\"b.pdf""#
+        );
+        assert_eq!(
+            content_disposition("报告.pdf").to_string(),
+            "attachment; filename=\"__.pdf\"; filename*=UTF-8''%E6%8A%A5%E5%91%8A.pdf"
+        );
+        assert_eq!(
+            content_type("text/plain; charset=gbk").to_string(),
+            "text/plain; charset=gbk"
+        );
+        assert_eq!(content_type("bogus"), APPLICATION_OCTET_STREAM);
+
+        let resp = attachment_response(MailAttachment {
+            filename: "a.html".to_string(),
+            mime_type: "text/html".to_string(),
+            data: b"<p>hi</p>".to_vec(),
+        });
+        let header = |name| resp.headers().get(name).unwrap().to_str().unwrap();
+        assert_eq!(header("Content-Security-Policy"), "sandbox");
+        assert_eq!(header("X-Content-Type-Options"), "nosniff");
+        assert_eq!(header("Content-Type"), "text/html");
+    }
+
+    #[test]
+    fn test_render() {
+        let mail = ParsedMail {
+            subject: "<hello>".to_string(),
+            from: "a@b.com".to_string(),
+            to: String::new(),
+            cc: String::new(),
+            date: String::new(),
+            text: "plain".to_string(),
+            html: None,
+            attachments: vec![MailAttachment {
+                filename: "a&b.pdf".to_string(),
+                mime_type: "application/pdf".to_string(),
+                data: vec![0; 2048],
+            }],
+        };
+        let page = render("id1", &mail, "exp=1&kid=k&sig=00", false);
+        assert!(page.contains("<h2>&lt;hello&gt;</h2>"));
+        assert!(!page.contains("<th>To</th>"));
+        assert!(page.contains(
+            r#"<a href="id1/attachments/0?exp=1&amp;kid=k&amp;sig=00">a&amp;b.pdf</a> (application/pdf, 2.0 KB)"#
+        ));
+        assert!(page.contains(r#"<pre class="body">plain</pre>"#));
+    }
+}
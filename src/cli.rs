@@ -0,0 +1,271 @@
+use crate::bot_server::feishu_client::{self, Client, DryRun, MessageType};
+use crate::bot_server::mail_url::attachment_url;
+use crate::bot_server::{HttpConfig, MailUrlGen, WebLogin};
+use crate::config::BaseConfig;
+use crate::privilege::PrivilegeConfig;
+use crate::smtp_server::envelope::MailEnvelope;
+use crate::smtp_server::greylist::Greylist;
+use crate::smtp_server::{self, SmtpConfig};
+use crate::store::Store;
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use serde_json::json;
+use std::io::Write;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Parser)]
+#[command(version, about = "Forward mails to Feishu groups")]
+pub struct Cli {
+    /// TOML config file, env vars override its values
+    #[arg(short, long, env = "MAILHOOK_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the SMTP and HTTP servers, the default
+    Serve,
+    /// Validate the configuration and exit
+    CheckConfig,
+    /// List the chats the bot is in with their mail addresses
+    ListChats,
+    /// Print the envelope and raw content of a stored mail
+    ShowMail {
+        id: String,
+        /// Only print the raw mail
+        #[arg(long)]
+        raw: bool,
+    },
+    /// Forward a stored mail to its recipient chats again
+    Redeliver { id: String },
+    /// Make every link of a mail stop working
+    RevokeLinks { id: String },
+    /// Delete old mails, mails past their chat's retention and expired greylist entries
+    Purge {
+        /// Keep mails received in the last N days
+        #[arg(long, default_value_t = 30)]
+        days: u64,
+    },
+    /// Print the Feishu messages a .eml file turns into, without sending them
+    Render {
+        file: PathBuf,
+        /// Envelope sender shown in the message
+        #[arg(long)]
+        from: Option<String>,
+        /// Recipient chat ids, repeatable
+        #[arg(long)]
+        to: Vec<String>,
+    },
+}
+
+pub fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Serve => unreachable!("serve is handled in main"),
+        Command::CheckConfig => check_config(),
+        Command::ListChats => list_chats(),
+        Command::ShowMail { id, raw } => show_mail(&id, raw),
+        Command::Redeliver { id } => redeliver(&id),
+        Command::RevokeLinks { id } => revoke_links(&id),
+        Command::Purge { days } => purge(days),
+        Command::Render { file, from, to } => render(&file, from, to),
+    }
+}
+
+fn open_store(base: &BaseConfig) -> Result<Store> {
+    if !Path::new(&base.store_path).exists() {
+        return Err(anyhow!("store not found: {}", base.store_path));
+    }
+    Store::new(Some(base.store_path.clone()), base.mail_domain.clone())
+}
+
+/// Runs every config constructor and reports all problems at once.
+fn check_config() -> Result<()> {
+    let mut errors = vec![];
+    let base = BaseConfig::load().map_err(|e| errors.push(format!("{:#}", e)));
+    let smtp = SmtpConfig::from_env().map_err(|e| errors.push(format!("smtp: {:#}", e)));
+    let http = HttpConfig::from_env().map_err(|e| errors.push(format!("http: {:#}", e)));
+    if let Err(e) = DryRun::from_env() {
+        errors.push(format!("feishu: {:#}", e));
+    }
+    if let Err(e) = MailUrlGen::check_env() {
+        errors.push(format!("mail link: {:#}", e));
+    }
+    if let Err(e) = WebLogin::check_env() {
+        errors.push(format!("web login: {:#}", e));
+    }
+    if let Ok(base) = &base {
+        let store_dir = match Path::new(&base.store_path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        if let Err(e) = PrivilegeConfig::from_env(&store_dir) {
+            errors.push(format!("privilege: {:#}", e));
+        }
+    }
+    let mut addrs = vec![];
+    if let Ok(smtp) = &smtp {
+        addrs.extend(smtp.listen.iter().chain(&smtp.tls_listen));
+    }
+    if let Ok(http) = &http {
+        addrs.extend(&http.listen);
+    }
+    for addr in addrs {
+        if let Err(e) = addr.to_socket_addrs() {
+            errors.push(format!("invalid listen address `{}`: {}", addr, e));
+        }
+    }
+    if errors.is_empty() {
+        println!("config ok");
+        return Ok(());
+    }
+    for error in &errors {
+        eprintln!("error: {}", error);
+    }
+    Err(anyhow!("{} config errors", errors.len()))
+}
+
+fn list_chats() -> Result<()> {
+    let store = open_store(&BaseConfig::load()?)?;
+    for chat_id in store.list_chats()? {
+        println!("{}\t{}@{}", chat_id, chat_id, store.mail_domain());
+    }
+    Ok(())
+}
+
+fn show_mail(id: &str, raw: bool) -> Result<()> {
+    let store = open_store(&BaseConfig::load()?)?;
+    let body = store
+        .get_mail(id)?
+        .ok_or_else(|| anyhow!("mail not found: {}", id))?;
+    let mut out = std::io::stdout().lock();
+    if !raw {
+        if let Some(envelope) = store.get_mail_envelope(id)? {
+            writeln!(out, "from: {}", envelope.mail_from)?;
+            writeln!(out, "rcpts: {}", envelope.rcpts.join(", "))?;
+            writeln!(out, "helo: {}", envelope.helo)?;
+            if let Some(ip) = envelope.client_ip {
+                writeln!(out, "client ip: {}", ip)?;
+            }
+            writeln!(out, "tls: {}", envelope.tls)?;
+            if let Some(auth) = envelope.auth {
+                writeln!(out, "auth: {}", auth)?;
+            }
+            let received = chrono::DateTime::from_timestamp(envelope.received_at, 0)
+                .unwrap_or_default()
+                .to_rfc2822();
+            writeln!(out, "received: {}", received)?;
+            writeln!(out)?;
+        }
+    }
+    out.write_all(&body)?;
+    Ok(())
+}
+
+fn redeliver(id: &str) -> Result<()> {
+    let base = BaseConfig::load()?;
+    let store = open_store(&base)?;
+    let client =
+        Client::new(base.feishu_app_id, base.feishu_app_secret).with_dry_run(DryRun::from_env()?);
+    let mail_url_gen = MailUrlGen::from_env(base.web_base_url, &store)?;
+    let mut failed = 0;
+    for (rcpt, ret) in smtp_server::redeliver(&client, &store, &mail_url_gen, id)? {
+        match ret {
+            Ok(()) => println!("{}: delivered", rcpt),
+            Err(e) => {
+                failed += 1;
+                println!("{}: failed: {}", rcpt, e);
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} recipients failed", failed));
+    }
+    Ok(())
+}
+
+fn revoke_links(id: &str) -> Result<()> {
+    let store = open_store(&BaseConfig::load()?)?;
+    if !store.revoke_mail_links(id)? {
+        return Err(anyhow!("mail not found: {}", id));
+    }
+    println!("links of {} revoked", id);
+    Ok(())
+}
+
+fn purge(days: u64) -> Result<()> {
+    let store = open_store(&BaseConfig::load()?)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let mails = store.purge_mails(now - (days * 24 * 3600) as i64)?;
+    println!("deleted {} mails older than {} days", mails, days);
+    let chat_mails = store.purge_chat_mails(now)?;
+    println!("removed {} mails past their chat's retention", chat_mails);
+    if let Some(greylist) = Greylist::from_env()? {
+        println!("deleted {} greylist entries", greylist.purge(&store)?);
+    }
+    store.flush()?;
+    Ok(())
+}
+
+/// Prints the send message requests delivery would make, file keys and the mail
+/// url are placeholders since nothing is uploaded or stored. Upload errors can't be
+/// predicted, only attachments Feishu refuses by size are turned into links.
+fn render(file: &Path, from: Option<String>, to: Vec<String>) -> Result<()> {
+    let body = std::fs::read(file).map_err(|e| anyhow!("read {} error: {}", file.display(), e))?;
+    let chats = if to.is_empty() {
+        vec!["<chat_id>".to_string()]
+    } else {
+        to
+    };
+    let envelope = MailEnvelope {
+        mail_from: from.unwrap_or_default(),
+        rcpts: chats.clone(),
+        ..Default::default()
+    };
+    let url = "<mail url>";
+    let mut rendered = smtp_server::render(&body, &envelope, url)?;
+    let links: Vec<_> = rendered
+        .files
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, data))| !feishu_client::can_upload(data.len()))
+        .map(|(n, (filename, _))| (filename.clone(), attachment_url(url, n)))
+        .collect();
+    smtp_server::add_attachment_links(&mut rendered.text, &links);
+    let mut messages = vec![];
+    for chat_id in chats {
+        messages.push(feishu_client::message_request(
+            chat_id.clone(),
+            MessageType::Text,
+            &rendered.text,
+        )?);
+        for (filename, data) in &rendered.files {
+            if !feishu_client::can_upload(data.len()) {
+                continue;
+            }
+            let content = json!({ "file_key": format!("<file_key of {}>", filename) });
+            messages.push(feishu_client::message_request(
+                chat_id.clone(),
+                MessageType::File,
+                &content,
+            )?);
+        }
+    }
+    let attachments: Vec<_> = rendered
+        .files
+        .iter()
+        .map(|(filename, data)| {
+            json!({
+                "file_name": filename,
+                "size": data.len(),
+                "upload": feishu_client::can_upload(data.len()),
+            })
+        })
+        .collect();
+    let output = json!({ "messages": messages, "attachments": attachments });
+    println!("{}", serde_json::to_string_pretty(&output)?);
+    Ok(())
+}
@@ -0,0 +1,324 @@
+use crate::logging;
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::env::VarError;
+use std::fs;
+use std::path::Path;
+use toml::Value;
+
+/// Every setting as `(env var, key in the config file)`. Each one can also be
+/// read from a file with `<ENV>_FILE` or `<key>_file`, meant for secrets.
+const SETTINGS: &[(&str, &str)] = &[
+    ("FEISHU_APP_ID", "feishu.app_id"),
+    ("FEISHU_APP_SECRET", "feishu.app_secret"),
+    ("FEISHU_VERIFICATION_TOKEN", "feishu.verification_token"),
+    ("FEISHU_DRY_RUN", "feishu.dry_run"),
+    ("MAIL_DOMAIN", "mail_domain"),
+    ("WEB_DOMAIN", "web_domain"),
+    ("WEB_BASE_URL", "web_base_url"),
+    ("WEB_LOGIN", "web.login"),
+    ("WEB_ADMINS", "web.admins"),
+    ("STORE_PATH", "store_path"),
+    ("MAIL_LINK_KEYS", "mail_link.keys"),
+    ("MAIL_LINK_TTL", "mail_link.ttl"),
+    ("RUN_AS_USER", "run_as_user"),
+    ("RUN_AS_GROUP", "run_as_group"),
+    ("CHROOT", "chroot"),
+    ("ALLOW_ROOT", "allow_root"),
+    ("LOG_LEVEL", "log.level"),
+    ("LOG_FORMAT", "log.format"),
+    ("OTEL_EXPORTER_OTLP_ENDPOINT", "otel.endpoint"),
+    ("OTEL_SERVICE_NAME", "otel.service_name"),
+    ("HTTP_LISTEN", "http.listen"),
+    ("HTTP_PROXY_PROTOCOL", "http.proxy_protocol"),
+    ("HTTP_TLS_CERT", "http.tls_cert"),
+    ("HTTP_TLS_KEY", "http.tls_key"),
+    ("SMTP_ENABLED", "smtp.enabled"),
+    ("SMTP_LISTEN", "smtp.listen"),
+    ("SMTPS_LISTEN", "smtp.tls_listen"),
+    ("SMTP_TLS_CERT", "smtp.tls_cert"),
+    ("SMTP_TLS_KEY", "smtp.tls_key"),
+    ("SMTP_PROXY_PROTOCOL", "smtp.proxy_protocol"),
+    ("SMTP_CONN_RATE_PER_IP", "smtp.conn_rate_per_ip"),
+    ("SMTP_MAIL_RATE_PER_IP", "smtp.mail_rate_per_ip"),
+    ("SMTP_MAIL_RATE_PER_CHAT", "smtp.mail_rate_per_chat"),
+    ("SMTP_MAX_MESSAGE_SIZE", "smtp.max_message_size"),
+    ("SMTP_SPOOL_THRESHOLD", "smtp.spool_threshold"),
+    ("SMTP_SPOOL_DIR", "smtp.spool_dir"),
+    ("SMTP_MAX_SESSIONS", "smtp.max_sessions"),
+    ("LMTP_LISTEN", "lmtp.listen"),
+    ("LMTP_SOCKET_MODE", "lmtp.socket_mode"),
+    ("PROXY_PROTOCOL_TRUSTED", "proxy_protocol.trusted"),
+    ("GREYLIST_DELAY", "greylist.delay"),
+    ("GREYLIST_TTL", "greylist.ttl"),
+    ("GREYLIST_ALLOW_SENDERS", "greylist.allow_senders"),
+    ("GREYLIST_ALLOW_NETWORKS", "greylist.allow_networks"),
+    ("DNSBL_ZONES", "dnsbl.zones"),
+    ("DNSBL_THRESHOLD", "dnsbl.threshold"),
+    ("DNSBL_RESOLVER", "dnsbl.resolver"),
+    ("DNSBL_CACHE_TTL", "dnsbl.cache_ttl"),
+];
+
+/// Settings that are replaced by `***` in the logs, as is anything read from a `_FILE`.
+const SECRETS: &[&str] = &[
+    "FEISHU_APP_SECRET",
+    "FEISHU_VERIFICATION_TOKEN",
+    "MAIL_LINK_KEYS",
+];
+
+static VALUES: OnceCell<HashMap<&'static str, String>> = OnceCell::new();
+
+/// Settings every command needs.
+pub struct BaseConfig {
+    pub feishu_app_id: String,
+    pub feishu_app_secret: String,
+    pub mail_domain: String,
+    /// Where the web server is reached from outside, without trailing slash.
+    pub web_base_url: String,
+    pub store_path: String,
+}
+
+impl BaseConfig {
+    pub fn load() -> Result<Self> {
+        Ok(BaseConfig {
+            feishu_app_id: required("FEISHU_APP_ID")?,
+            feishu_app_secret: required("FEISHU_APP_SECRET")?,
+            mail_domain: required("MAIL_DOMAIN")?,
+            web_base_url: web_base_url()?,
+            store_path: var("STORE_PATH").unwrap_or_else(|_| "store.sqlite".to_string()),
+        })
+    }
+}
+
+/// Reads the optional config file and resolves env overrides and `_FILE` secrets.
+/// Env vars win over the file, a plain value wins over its `_FILE` variant.
+pub fn load(path: Option<&Path>) -> Result<()> {
+    let file = match path {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .map_err(|e| anyhow!("read config {} error: {}", path.display(), e))?;
+            parse(&text).with_context(|| format!("invalid config {}", path.display()))?
+        }
+        None => HashMap::new(),
+    };
+    let values = resolve(&file, |name| std::env::var(name).ok())?;
+    for name in SECRETS {
+        if let Some(secret) = values.get(name) {
+            logging::add_secret(secret);
+        }
+    }
+    VALUES
+        .set(values)
+        .map_err(|_| anyhow!("config already loaded"))
+}
+
+/// Drop-in for `std::env::var` that also sees the config file once loaded.
+pub fn var(name: &str) -> Result<String, VarError> {
+    match VALUES.get() {
+        Some(values) if SETTINGS.iter().any(|(env, _)| *env == name) => {
+            values.get(name).cloned().ok_or(VarError::NotPresent)
+        }
+        _ => std::env::var(name),
+    }
+}
+
+/// `WEB_BASE_URL` may carry a path prefix for reverse proxies, without it the
+/// url is built from `WEB_DOMAIN`, https when the web server has a certificate.
+fn web_base_url() -> Result<String> {
+    if let Ok(url) = var("WEB_BASE_URL") {
+        if !url.is_empty() {
+            return parse_base_url(&url).context("WEB_BASE_URL");
+        }
+    }
+    let domain = required("WEB_DOMAIN")?;
+    let scheme = match var("HTTP_TLS_CERT") {
+        Ok(cert) if !cert.is_empty() => "https",
+        _ => "http",
+    };
+    Ok(format!("{}://{}", scheme, domain))
+}
+
+fn parse_base_url(url: &str) -> Result<String> {
+    let rest = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .ok_or_else(|| anyhow!("`{}` must start with http:// or https://", url))?;
+    if rest.is_empty() || rest.starts_with('/') || rest.contains(['?', '#']) {
+        return Err(anyhow!("`{}` is not a valid base url", url));
+    }
+    Ok(url.trim_end_matches('/').to_string())
+}
+
+pub fn required(name: &str) -> Result<String> {
+    match var(name) {
+        Ok(value) if !value.is_empty() => Ok(value),
+        _ => Err(anyhow!(
+            "`{}` must be set, either as env var or as `{}` in the config file",
+            name,
+            key_of(name)
+        )),
+    }
+}
+
+fn key_of(name: &str) -> &str {
+    SETTINGS
+        .iter()
+        .find(|(env, _)| *env == name)
+        .map(|(_, key)| *key)
+        .unwrap_or(name)
+}
+
+/// Flattens the TOML into `section.key` pairs, arrays are joined with commas
+/// like the env vars.
+fn parse(text: &str) -> Result<HashMap<String, String>> {
+    let table: toml::Table = text.parse()?;
+    let mut values = HashMap::new();
+    flatten("", &table, &mut values)?;
+    for key in values.keys() {
+        let setting = key.strip_suffix("_file").unwrap_or(key);
+        if !SETTINGS.iter().any(|(_, k)| *k == setting) {
+            return Err(anyhow!("unknown key `{}`", key));
+        }
+    }
+    Ok(values)
+}
+
+fn flatten(prefix: &str, table: &toml::Table, out: &mut HashMap<String, String>) -> Result<()> {
+    for (key, value) in table {
+        let key = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        let value = match value {
+            Value::Table(table) => {
+                flatten(&key, table, out)?;
+                continue;
+            }
+            Value::Array(items) => items
+                .iter()
+                .map(scalar)
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("`{}`", key))?
+                .join(","),
+            value => scalar(value).with_context(|| format!("`{}`", key))?,
+        };
+        out.insert(key, value);
+    }
+    Ok(())
+}
+
+fn scalar(value: &Value) -> Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        value => Err(anyhow!("unsupported value: {}", value)),
+    }
+}
+
+fn resolve(
+    file: &HashMap<String, String>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<HashMap<&'static str, String>> {
+    let mut values = HashMap::new();
+    for (name, key) in SETTINGS {
+        let env_file = format!("{}_FILE", name);
+        let key_file = format!("{}_file", key);
+        let value = if let Some(value) = env(name) {
+            value
+        } else if let Some(path) = env(&env_file) {
+            read_secret(&path).with_context(|| format!("`{}`", env_file))?
+        } else if let Some(value) = file.get(*key) {
+            value.clone()
+        } else if let Some(path) = file.get(&key_file) {
+            read_secret(path).with_context(|| format!("`{}`", key_file))?
+        } else {
+            continue;
+        };
+        values.insert(*name, value);
+    }
+    Ok(values)
+}
+
+fn read_secret(path: &str) -> Result<String> {
+    let secret = fs::read_to_string(path).map_err(|e| anyhow!("read {} error: {}", path, e))?;
+    let secret = secret.trim_end_matches(['\r', '\n']).to_string();
+    logging::add_secret(&secret);
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_parse() {
+        let values = parse(
+            r#"
+            mail_domain = "mail.xcf.io"
+            [smtp]
+            listen = ["0.0.0.0:25", "[::]:25"]
+            max_message_size = 1024
+            [greylist]
+            delay = 300
+            "#,
+        )
+        .unwrap();
+        assert_eq!(values["mail_domain"], "mail.xcf.io");
+        assert_eq!(values["smtp.listen"], "0.0.0.0:25,[::]:25");
+        assert_eq!(values["smtp.max_message_size"], "1024");
+        assert_eq!(values["greylist.delay"], "300");
+
+        let err = parse("[smtp]\nlisen = \"0.0.0.0:25\"").unwrap_err();
+        assert_eq!(err.to_string(), "unknown key `smtp.lisen`");
+        assert!(parse("mail_domain = ").is_err());
+    }
+
+    #[test]
+    fn test_parse_base_url() {
+        assert_eq!(
+            parse_base_url("https://example.com/mailhook/").unwrap(),
+            "https://example.com/mailhook"
+        );
+        assert_eq!(
+            parse_base_url("http://example.com:8088").unwrap(),
+            "http://example.com:8088"
+        );
+        assert!(parse_base_url("example.com").is_err());
+        assert!(parse_base_url("https://").is_err());
+        assert!(parse_base_url("https://example.com/?a=1").is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut secret = tempfile::NamedTempFile::new().unwrap();
+        writeln!(secret, "from file").unwrap();
+        let secret_path = secret.path().to_string_lossy().into_owned();
+
+        let file = parse(&format!(
+            "mail_domain = \"file.xcf.io\"\nweb_domain = \"web.xcf.io\"\n\
+            [feishu]\napp_id_file = \"{}\"\napp_secret = \"file secret\"",
+            secret_path
+        ))
+        .unwrap();
+        let env = |name: &str| match name {
+            "MAIL_DOMAIN" => Some("env.xcf.io".to_string()),
+            "FEISHU_APP_SECRET_FILE" => Some(secret_path.clone()),
+            _ => None,
+        };
+        let values = resolve(&file, env).unwrap();
+        assert_eq!(values["MAIL_DOMAIN"], "env.xcf.io");
+        assert_eq!(values["WEB_DOMAIN"], "web.xcf.io");
+        assert_eq!(values["FEISHU_APP_ID"], "from file");
+        assert_eq!(values["FEISHU_APP_SECRET"], "from file");
+        assert!(!values.contains_key("STORE_PATH"));
+        assert_eq!(logging::redact("key from file".to_string()), "key ***");
+
+        let env = |name: &str| (name == "FEISHU_APP_ID_FILE").then(|| "/nonexistent".to_string());
+        assert!(resolve(&HashMap::new(), env).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use crate::shutdown::Shutdown;
+use crate::{config, metrics};
+use anyhow::{anyhow, Result};
+use log::{error, info};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// How often a non-blocking listener checks for new connections and shutdown.
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+
+/// systemd passes activated sockets starting from fd 3.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets passed by systemd socket activation, keyed by `FileDescriptorName=`.
+static ACTIVATED: Lazy<Mutex<HashMap<String, Vec<RawFd>>>> =
+    Lazy::new(|| Mutex::new(activated_fds()));
+
+/// Running accept loops by listener name, a name stays with 0 once its loop exited.
+static ACCEPTING: Lazy<Mutex<HashMap<String, usize>>> = Lazy::new(Mutex::default);
+
+fn activated_fds() -> HashMap<String, Vec<RawFd>> {
+    let pid = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok());
+    if pid != Some(std::process::id()) {
+        return HashMap::new();
+    }
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0);
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    fd_names(count, &names)
+}
+
+fn fd_names(count: usize, names: &str) -> HashMap<String, Vec<RawFd>> {
+    let names: Vec<&str> = names.split(':').collect();
+    let mut fds: HashMap<String, Vec<RawFd>> = HashMap::new();
+    for i in 0..count {
+        let name = match names.get(i) {
+            Some(name) if !name.is_empty() => name,
+            _ => "unknown",
+        };
+        fds.entry(name.to_string())
+            .or_default()
+            .push(SD_LISTEN_FDS_START + i as RawFd);
+    }
+    fds
+}
+
+/// Comma separated `host:port` list, IPv6 as `[::]:25`.
+pub fn parse_addrs(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Reads a listen address list from `var`, falling back to `default` when unset.
+pub fn addrs_from_env(var: &str, default: &str) -> Vec<String> {
+    parse_addrs(&config::var(var).unwrap_or_else(|_| default.to_string()))
+}
+
+/// Uses the sockets systemd passed for `name` if there are any, otherwise binds `addrs`.
+pub fn tcp_listeners(name: &str, addrs: &[String]) -> Result<Vec<TcpListener>> {
+    let fds = ACTIVATED.lock().unwrap().remove(name);
+    if let Some(fds) = fds {
+        info!("{}: {} sockets from systemd", name, fds.len());
+        // SAFETY: the descriptors were passed to this process by systemd and are
+        // removed from `ACTIVATED`, so each one is owned exactly once.
+        return Ok(fds
+            .into_iter()
+            .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+            .collect());
+    }
+    addrs
+        .iter()
+        .map(|addr| {
+            let listener = TcpListener::bind(addr)
+                .map_err(|e| anyhow!("{} bind {} error: {}", name, addr, e))?;
+            info!("{}: {}", name, addr);
+            Ok(listener)
+        })
+        .collect()
+}
+
+/// Accepts connections until `shutdown` is triggered. A blocking accept can't be
+/// interrupted, so the listener must be non-blocking and is polled instead.
+pub fn accept_loop<S>(
+    name: &str,
+    shutdown: &Shutdown,
+    accept: impl Fn() -> io::Result<S>,
+    mut handle: impl FnMut(S),
+) {
+    *ACCEPTING
+        .lock()
+        .unwrap()
+        .entry(name.to_string())
+        .or_default() += 1;
+    while !shutdown.is_stopping() {
+        match accept() {
+            Ok(stream) => {
+                metrics::CONNECTIONS.with_label_values(&[name]).inc();
+                handle(stream)
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL),
+            Err(e) => {
+                error!("{} accept error: {}", name, e);
+                thread::sleep(ACCEPT_POLL);
+            }
+        }
+    }
+    if let Some(count) = ACCEPTING.lock().unwrap().get_mut(name) {
+        *count -= 1;
+    }
+    info!("{}: stopped accepting", name);
+}
+
+/// Snapshot of the running accept loops, for the readiness probe.
+pub fn accepting() -> HashMap<String, usize> {
+    ACCEPTING.lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fd_names() {
+        let fds = fd_names(3, "smtp:http:smtp");
+        assert_eq!(fds["smtp"], vec![3, 5]);
+        assert_eq!(fds["http"], vec![4]);
+        assert_eq!(fd_names(1, "")["unknown"], vec![3]);
+        assert_eq!(
+            parse_addrs(" 0.0.0.0:25, [::]:2525,"),
+            vec!["0.0.0.0:25", "[::]:2525"]
+        );
+    }
+}
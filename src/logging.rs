@@ -0,0 +1,199 @@
+use crate::{config, telemetry};
+use anyhow::{anyhow, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::io::Write;
+use std::sync::RwLock;
+
+/// Dependencies are too noisy at debug, only these crates are logged.
+const TARGETS: &[&str] = &["mailhook", "mailin", "actix_web"];
+
+/// Values replaced by `***` in every log line.
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+thread_local! {
+    /// The mail the current thread is working on, set from `data_start` until the
+    /// transaction is cleared so storage, parsing and Feishu calls log the same id.
+    static MAIL_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    Json,
+}
+
+struct Logger {
+    level: LevelFilter,
+    format: Format,
+    /// Otherwise only errors go to stderr and the rest to stdout.
+    stderr_only: bool,
+}
+
+/// `LOG_LEVEL` overrides `default_level`, `LOG_FORMAT=json` writes one JSON object per line.
+pub fn init(default_level: LevelFilter, stderr_only: bool) -> Result<()> {
+    let level = match config::var("LOG_LEVEL") {
+        Ok(level) => level
+            .parse()
+            .map_err(|_| anyhow!("invalid LOG_LEVEL: {}", level))?,
+        Err(_) => default_level,
+    };
+    let format = match config::var("LOG_FORMAT").as_deref() {
+        Err(_) | Ok("text") => Format::Text,
+        Ok("json") => Format::Json,
+        Ok(format) => {
+            return Err(anyhow!(
+                "invalid LOG_FORMAT: {}, expected text or json",
+                format
+            ))
+        }
+    };
+    let logger = Logger {
+        level,
+        format,
+        stderr_only,
+    };
+    log::set_boxed_logger(Box::new(logger)).map_err(|e| anyhow!("init logger error: {}", e))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+pub fn set_mail_id(id: Option<&str>) {
+    MAIL_ID.with(|mail_id| *mail_id.borrow_mut() = id.map(str::to_string));
+}
+
+pub fn mail_id() -> Option<String> {
+    MAIL_ID.with(|mail_id| mail_id.borrow().clone())
+}
+
+/// Registers a value that must never show up in logs, e.g. the app secret.
+pub fn add_secret(secret: &str) {
+    // short values would mangle unrelated text
+    if secret.len() < 6 {
+        return;
+    }
+    let mut secrets = SECRETS.write().unwrap();
+    if !secrets.iter().any(|s| s == secret) {
+        secrets.push(secret.to_string());
+    }
+}
+
+/// Replaces every registered secret with `***`.
+pub fn redact(text: String) -> String {
+    let secrets = SECRETS.read().unwrap();
+    if !secrets.iter().any(|s| text.contains(s.as_str())) {
+        return text;
+    }
+    secrets
+        .iter()
+        .fold(text, |text, secret| text.replace(secret.as_str(), "***"))
+}
+
+impl Logger {
+    fn format(&self, record: &Record, mail_id: Option<String>) -> String {
+        let msg = redact(record.args().to_string());
+        let now = chrono::Local::now();
+        match self.format {
+            Format::Text => {
+                let mut line = format!(
+                    "{} [{}] {}: ",
+                    now.format("%H:%M:%S"),
+                    record.level(),
+                    record.target()
+                );
+                if let Some(id) = mail_id {
+                    line.push_str(&format!("[{}] ", id));
+                }
+                line + &msg
+            }
+            Format::Json => {
+                let mut line = json!({
+                    "ts": now.to_rfc3339(),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "msg": msg,
+                });
+                if let Some(id) = mail_id {
+                    line["mail_id"] = Value::String(id);
+                }
+                if let Some(id) = telemetry::trace_id() {
+                    line["trace_id"] = Value::String(id);
+                }
+                line.to_string()
+            }
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let target = metadata.target();
+        metadata.level() <= self.level
+            && TARGETS.iter().any(|t| {
+                target
+                    .strip_prefix(t)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = self.format(record, mail_id());
+        let _ = if self.stderr_only || record.level() == Level::Error {
+            writeln!(std::io::stderr(), "{}", line)
+        } else {
+            writeln!(std::io::stdout(), "{}", line)
+        };
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        add_secret("app-secret-value");
+        add_secret("app-secret-value");
+        let secrets = SECRETS.read().unwrap();
+        assert_eq!(
+            secrets.iter().filter(|s| *s == "app-secret-value").count(),
+            1
+        );
+        drop(secrets);
+        let logger = Logger {
+            level: LevelFilter::Info,
+            format: Format::Json,
+            stderr_only: true,
+        };
+        let record = Record::builder()
+            .args(format_args!("token for app-secret-value failed"))
+            .level(Level::Warn)
+            .target("mailhook::smtp_server")
+            .build();
+        let line: Value =
+            serde_json::from_str(&logger.format(&record, Some("id-1".to_string()))).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["msg"], "token for *** failed");
+        assert_eq!(line["mail_id"], "id-1");
+
+        assert!(logger.enabled(record.metadata()));
+        let other = Metadata::builder()
+            .level(Level::Warn)
+            .target("mailhook_other")
+            .build();
+        assert!(!logger.enabled(&other));
+        let debug = Metadata::builder()
+            .level(Level::Debug)
+            .target("mailhook")
+            .build();
+        assert!(!logger.enabled(&debug));
+    }
+}
@@ -1,45 +1,247 @@
 mod bot_dto;
 mod bot_server;
+mod cli;
+mod config;
+mod listen;
+mod logging;
+mod metrics;
+mod privilege;
+mod proxy_protocol;
+mod shutdown;
 mod smtp_server;
 mod store;
+mod telemetry;
+mod tls;
 
-use crate::bot_server::feishu_client::Client;
-use crate::bot_server::MailUrlGen;
+use crate::bot_server::feishu_client::{Client, DryRun};
+use crate::bot_server::{HttpConfig, MailUrlGen, WebLogin};
+use crate::cli::{Cli, Command};
+use crate::config::BaseConfig;
+use crate::privilege::PrivilegeConfig;
+use crate::shutdown::Shutdown;
+use crate::smtp_server::greylist::Greylist;
+use crate::smtp_server::{SmtpConfig, SmtpListeners};
 use crate::store::Store;
-use anyhow::Result;
-use simplelog::{ConfigBuilder, LevelFilter, TermLogger, TerminalMode};
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use log::{error, info, warn, LevelFilter};
+use nix::sys::signal::{SigSet, Signal};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long in-flight deliveries and requests get to finish after SIGTERM.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often mails past their chat's retention are removed.
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+enum Event {
+    Signal(Signal),
+    Exited(&'static str, Result<()>),
+}
+
+/// Runs a server in its own thread and reports when it exits, a panic counts as an error.
+fn spawn_server(
+    name: &'static str,
+    events: &Sender<Event>,
+    f: impl FnOnce() -> Result<()> + Send + 'static,
+) {
+    let events = events.clone();
+    thread::spawn(move || {
+        let ret = panic::catch_unwind(AssertUnwindSafe(f))
+            .unwrap_or_else(|_| Err(anyhow!("{} server panicked", name)));
+        let _ = events.send(Event::Exited(name, ret));
+    });
+}
 
 fn main() -> Result<()> {
-    let config = ConfigBuilder::new()
-        .add_filter_allow_str("mailhook")
-        .add_filter_allow_str("mailin")
-        .add_filter_allow_str("actix_web")
-        .build();
-    TermLogger::init(
-        LevelFilter::Debug,
-        config,
-        TerminalMode::Mixed,
-        simplelog::ColorChoice::Auto,
-    )?;
-    let feishu_app_id = std::env::var("FEISHU_APP_ID").expect("`FEISHU_APP_ID` must be set");
-    let feishu_app_secret =
-        std::env::var("FEISHU_APP_SECRET").expect("`FEISHU_APP_SECRET` must be set");
-    let mail_domain = std::env::var("MAIL_DOMAIN").expect("`MAIL_DOMAIN` must be set");
-    let web_domain = std::env::var("WEB_DOMAIN").expect("`WEB_DOMAIN` must be set");
-    let store_path = std::env::var("STORE_PATH").unwrap_or_else(|_| "store.sqlite".to_string());
-    let client = Client::new(feishu_app_id, feishu_app_secret.clone());
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Serve);
+    config::load(cli.config.as_deref())?;
+    // keep stdout clean for the maintenance commands
+    match command {
+        Command::Serve => logging::init(LevelFilter::Debug, false)?,
+        _ => logging::init(LevelFilter::Warn, true)?,
+    }
+    match command {
+        Command::Serve => serve(),
+        command => cli::run(command),
+    }
+}
+
+fn serve() -> Result<()> {
+    // block before any thread is spawned so only the signal thread receives them
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGINT);
+    signals.thread_block()?;
+    let BaseConfig {
+        feishu_app_id,
+        feishu_app_secret,
+        mail_domain,
+        web_base_url,
+        store_path,
+    } = BaseConfig::load()?;
+    let mut smtp_config = SmtpConfig::from_env()?;
+    let http_config = HttpConfig::from_env()?;
+    // opened before dropping privileges, the file may live outside the chroot
+    let dry_run = DryRun::from_env()?;
+    // the exporter may need CA certificates from outside the chroot too
+    let tracer_provider = telemetry::init()?;
+    let store_dir = match Path::new(&store_path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let privilege_config = PrivilegeConfig::from_env(&store_dir)?;
+    let smtp_listeners = SmtpListeners::bind(&smtp_config)?;
+    let http_listeners = listen::tcp_listeners("http", &http_config.listen)?;
+    let store_path = privilege_config.inner_path(Path::new(&store_path));
+    if privilege_config.chroot.is_some() {
+        // the temp dir is usually missing inside the chroot
+        smtp_config.spool.dir = match config::var("SMTP_SPOOL_DIR") {
+            Ok(dir) => privilege_config
+                .inside_chroot(Path::new(&dir))
+                .map_err(|e| anyhow!("SMTP_SPOOL_DIR: {}", e))?,
+            Err(_) => privilege_config.inner_path(&store_dir.join("spool")),
+        };
+    }
+    privilege::drop_privileges(&privilege_config)?;
+    smtp_config.spool.create_dir()?;
+
+    if dry_run.is_some() {
+        warn!("FEISHU_DRY_RUN is set, requests to Feishu are recorded instead of sent");
+    }
+    let client = Client::new(feishu_app_id, feishu_app_secret).with_dry_run(dry_run);
     let client_clone = client.clone();
-    let store = Store::new(Some(store_path), mail_domain.clone())?;
+    let store = Store::new(
+        Some(store_path.to_string_lossy().into_owned()),
+        mail_domain.clone(),
+    )?;
     let store_clone = store.clone();
-    let mail_url_gen = MailUrlGen::new(web_domain, feishu_app_secret);
+    let http_store = store.clone();
+    let web_login = WebLogin::from_env(web_base_url.clone(), &store)?;
+    let mail_url_gen = MailUrlGen::from_env(web_base_url, &store)?;
     let mail_url_gen_clone = mail_url_gen.clone();
+    let shutdown = Shutdown::default();
+    let smtp_shutdown = shutdown.clone();
+    let http_shutdown = shutdown.clone();
+
+    let (events, received) = mpsc::channel();
+    let signal_events = events.clone();
+    thread::spawn(move || loop {
+        if let Ok(signal) = signals.wait() {
+            if signal_events.send(Event::Signal(signal)).is_err() {
+                return;
+            }
+        }
+    });
+    let purge_store = store.clone();
+    let purge_shutdown = shutdown.clone();
+    let purge_greylist = smtp_config.greylist.clone();
     thread::spawn(move || {
-        let ret = smtp_server::serve(client_clone, store_clone, mail_url_gen_clone);
-        if let Err(e) = ret {
-            panic!("smtp server error: {}", e);
+        while !purge_shutdown.wait_stopping(PURGE_INTERVAL) {
+            purge_expired(&purge_store, purge_greylist.as_ref());
         }
     });
-    bot_server::serve(client, store, mail_url_gen)?;
+    spawn_server("smtp", &events, move || {
+        smtp_server::serve(
+            client_clone,
+            store_clone,
+            mail_url_gen_clone,
+            smtp_config,
+            smtp_listeners,
+            smtp_shutdown,
+        )
+    });
+    spawn_server("http", &events, move || {
+        bot_server::serve(
+            client,
+            http_store,
+            mail_url_gen,
+            web_login,
+            http_config,
+            http_listeners,
+            http_shutdown,
+        )
+        .map_err(Into::into)
+    });
+
+    // either server exiting takes the whole process down, so a supervisor
+    // (systemd, docker) restarts it instead of leaving it half dead
+    let mut running = 2;
+    let mut failed = false;
+    match received.recv()? {
+        Event::Signal(signal) => info!("received {}, shutting down", signal),
+        Event::Exited(name, ret) => {
+            running -= 1;
+            failed = true;
+            match ret {
+                Ok(()) => error!("{} server exited unexpectedly, shutting down", name),
+                Err(e) => error!("{} server error: {}, shutting down", name, e),
+            }
+        }
+    }
+    shutdown.trigger();
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    if !shutdown.wait_deliveries(SHUTDOWN_TIMEOUT) {
+        warn!("deliveries still running after {:?}", SHUTDOWN_TIMEOUT);
+    }
+    while running > 0 {
+        match received.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Event::Exited(name, ret)) => {
+                running -= 1;
+                match ret {
+                    Ok(()) => info!("{} server stopped", name),
+                    Err(e) => {
+                        failed = true;
+                        error!("{} server error: {}", name, e);
+                    }
+                }
+            }
+            Ok(Event::Signal(signal)) => {
+                warn!("received {} again, exiting now", signal);
+                break;
+            }
+            Err(_) => {
+                warn!(
+                    "servers still running after {:?}, exiting",
+                    SHUTDOWN_TIMEOUT
+                );
+                break;
+            }
+        }
+    }
+    store.flush()?;
+    if let Some(provider) = tracer_provider {
+        if let Err(e) = provider.shutdown() {
+            warn!("flush traces error: {}", e);
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
     Ok(())
 }
+
+/// Applies the per-chat retention set on the admin page and drops expired
+/// greylist triplets.
+fn purge_expired(store: &Store, greylist: Option<&Greylist>) {
+    if let Some(greylist) = greylist {
+        match greylist.purge(store) {
+            Ok(0) => {}
+            Ok(n) => info!("purged {} expired greylist triplets", n),
+            Err(e) => error!("purge greylist error: {}", e),
+        }
+    }
+    let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(now) => now.as_secs() as i64,
+        Err(e) => return error!("purge error: {}", e),
+    };
+    match store.purge_chat_mails(now) {
+        Ok(0) => {}
+        Ok(n) => info!("purged {} mails past their chat's retention", n),
+        Err(e) => error!("purge chat mails error: {}", e),
+    }
+}
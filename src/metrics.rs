@@ -0,0 +1,127 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, Encoder, Histogram, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+
+/// Accepted connections by listener: smtp, smtps or lmtp.
+pub static CONNECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "mailhook_connections_total",
+        "Accepted connections",
+        &["listener"]
+    )
+    .unwrap()
+});
+
+pub static MESSAGES_ACCEPTED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("mailhook_messages_accepted_total", "Accepted messages").unwrap()
+});
+
+/// Rejected commands by reason, a rejected RCPT counts once per recipient.
+pub static MESSAGES_REJECTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "mailhook_messages_rejected_total",
+        "Rejected messages",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+pub static MESSAGE_SIZE: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "mailhook_message_size_bytes",
+        "Size of accepted messages",
+        exponential_buckets(1024.0, 4.0, 10).unwrap()
+    )
+    .unwrap()
+});
+
+pub static PARSE_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "mailhook_parse_failures_total",
+        "Mails that could not be parsed"
+    )
+    .unwrap()
+});
+
+/// Deliveries that are stored but not yet forwarded to every chat.
+pub static DELIVERIES_IN_FLIGHT: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "mailhook_deliveries_in_flight",
+        "Deliveries being forwarded to Feishu"
+    )
+    .unwrap()
+});
+
+/// Feishu API calls by endpoint and result code, `error` when no response was received.
+pub static FEISHU_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "mailhook_feishu_requests_total",
+        "Feishu API calls",
+        &["endpoint", "code"]
+    )
+    .unwrap()
+});
+
+pub static TOKEN_REFRESHES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "mailhook_feishu_token_refreshes_total",
+        "Tenant access token requests",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static STORE_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "mailhook_store_duration_seconds",
+        "Latency of store operations",
+        &["op"],
+        exponential_buckets(0.0001, 4.0, 9).unwrap()
+    )
+    .unwrap()
+});
+
+pub fn reject(reason: &str) {
+    MESSAGES_REJECTED.with_label_values(&[reason]).inc();
+}
+
+/// All registered metrics in the Prometheus text format.
+pub fn render() -> String {
+    // registered on first use, force them so unlabelled metrics show up as zero
+    Lazy::force(&CONNECTIONS);
+    Lazy::force(&MESSAGES_ACCEPTED);
+    Lazy::force(&MESSAGES_REJECTED);
+    Lazy::force(&MESSAGE_SIZE);
+    Lazy::force(&PARSE_FAILURES);
+    Lazy::force(&DELIVERIES_IN_FLIGHT);
+    Lazy::force(&FEISHU_REQUESTS);
+    Lazy::force(&TOKEN_REFRESHES);
+    Lazy::force(&STORE_DURATION);
+    let mut buf = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buf)
+        .unwrap();
+    String::from_utf8(buf).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        reject("greylisted");
+        FEISHU_REQUESTS
+            .with_label_values(&["im/v1/messages", "0"])
+            .inc();
+        let text = render();
+        assert!(text.contains("mailhook_parse_failures_total 0"));
+        assert!(text.contains(r#"mailhook_messages_rejected_total{reason="greylisted"}"#));
+        assert!(
+            text.contains(r#"mailhook_feishu_requests_total{code="0",endpoint="im/v1/messages"}"#)
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use crate::config;
+use anyhow::{anyhow, Result};
+use log::info;
+use nix::unistd::{
+    chdir, chroot, geteuid, getgid, setgid, setgroups, setuid, Gid, Group, Uid, User,
+};
+use std::path::{Path, PathBuf};
+
+/// Who the process keeps running as once every socket is bound.
+pub struct PrivilegeConfig {
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub chroot: Option<PathBuf>,
+    /// Set `ALLOW_ROOT=true` to keep running as root.
+    pub allow_root: bool,
+}
+
+impl PrivilegeConfig {
+    /// `CHROOT=true` chroots to `store_dir`, the directory of the sqlite store.
+    pub fn from_env(store_dir: &Path) -> Result<Self> {
+        let enabled = |var| matches!(config::var(var).as_deref(), Ok("true") | Ok("1"));
+        let non_empty = |var| config::var(var).ok().filter(|v: &String| !v.is_empty());
+        Ok(PrivilegeConfig {
+            user: non_empty("RUN_AS_USER"),
+            group: non_empty("RUN_AS_GROUP"),
+            chroot: if enabled("CHROOT") {
+                Some(store_dir.canonicalize()?)
+            } else {
+                None
+            },
+            allow_root: enabled("ALLOW_ROOT"),
+        })
+    }
+
+    /// Where `path` ends up once chrooted.
+    pub fn inner_path(&self, path: &Path) -> PathBuf {
+        let Some(root) = &self.chroot else {
+            return path.to_path_buf();
+        };
+        let path = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => match parent.canonicalize() {
+                Ok(parent) => parent.join(path.file_name().unwrap_or_default()),
+                Err(_) => path.to_path_buf(),
+            },
+            _ => match std::env::current_dir() {
+                Ok(dir) => dir.join(path),
+                Err(_) => path.to_path_buf(),
+            },
+        };
+        match path.strip_prefix(root) {
+            Ok(rest) => Path::new("/").join(rest),
+            Err(_) => path,
+        }
+    }
+
+    /// Like `inner_path` for an existing directory, but fails if it is outside the chroot.
+    pub fn inside_chroot(&self, dir: &Path) -> Result<PathBuf> {
+        let Some(root) = &self.chroot else {
+            return Ok(dir.to_path_buf());
+        };
+        let dir = dir
+            .canonicalize()
+            .map_err(|e| anyhow!("{} error: {}", dir.display(), e))?;
+        match dir.strip_prefix(root) {
+            Ok(rest) => Ok(Path::new("/").join(rest)),
+            Err(_) => Err(anyhow!(
+                "{} is outside the chroot {}",
+                dir.display(),
+                root.display()
+            )),
+        }
+    }
+}
+
+/// Chroots and switches to the configured user. Called after the listeners are
+/// bound, refuses to go on as root unless allowed.
+pub fn drop_privileges(config: &PrivilegeConfig) -> Result<()> {
+    // look the names up before chroot, /etc/passwd is usually gone afterwards
+    let user = match &config.user {
+        Some(name) => Some(lookup_user(name)?),
+        None => None,
+    };
+    let gid = match &config.group {
+        Some(name) => Some(lookup_group(name)?),
+        None => user.as_ref().map(|(_, gid)| *gid),
+    };
+    if let Some(root) = &config.chroot {
+        chroot(root).map_err(|e| anyhow!("chroot {} error: {}", root.display(), e))?;
+        chdir("/")?;
+        info!("chroot: {}", root.display());
+    }
+    if let Some(gid) = gid {
+        setgroups(&[gid]).map_err(|e| anyhow!("setgroups error: {}", e))?;
+        setgid(gid).map_err(|e| anyhow!("setgid {} error: {}", gid, e))?;
+    }
+    if let Some((uid, _)) = user {
+        setuid(uid).map_err(|e| anyhow!("setuid {} error: {}", uid, e))?;
+        info!("running as {}:{}", uid, getgid());
+    }
+    if geteuid().is_root() && !config.allow_root {
+        return Err(anyhow!(
+            "refusing to run as root, set `RUN_AS_USER` or `ALLOW_ROOT=true`"
+        ));
+    }
+    Ok(())
+}
+
+/// A numeric uid without a passwd entry (e.g. in a scratch container) uses the
+/// same number as its gid.
+fn lookup_user(name: &str) -> Result<(Uid, Gid)> {
+    if let Ok(uid) = name.parse::<u32>() {
+        return Ok(match User::from_uid(Uid::from_raw(uid))? {
+            Some(user) => (user.uid, user.gid),
+            None => (Uid::from_raw(uid), Gid::from_raw(uid)),
+        });
+    }
+    User::from_name(name)?
+        .map(|user| (user.uid, user.gid))
+        .ok_or_else(|| anyhow!("unknown user: {}", name))
+}
+
+fn lookup_group(name: &str) -> Result<Gid> {
+    if let Ok(gid) = name.parse::<u32>() {
+        return Ok(Gid::from_raw(gid));
+    }
+    Group::from_name(name)?
+        .map(|group| group.gid)
+        .ok_or_else(|| anyhow!("unknown group: {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inner_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let config = PrivilegeConfig {
+            user: None,
+            group: None,
+            chroot: Some(root.clone()),
+            allow_root: false,
+        };
+        assert_eq!(
+            config.inner_path(&root.join("store.sqlite")),
+            PathBuf::from("/store.sqlite")
+        );
+        assert_eq!(
+            config.inner_path(Path::new("/elsewhere/store.sqlite")),
+            PathBuf::from("/elsewhere/store.sqlite")
+        );
+
+        std::fs::create_dir(root.join("spool")).unwrap();
+        assert_eq!(
+            config.inside_chroot(&root.join("spool")).unwrap(),
+            PathBuf::from("/spool")
+        );
+        assert!(config.inside_chroot(&std::env::temp_dir()).is_err());
+        assert!(config.inside_chroot(&root.join("missing")).is_err());
+    }
+}
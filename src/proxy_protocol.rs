@@ -0,0 +1,243 @@
+use crate::config;
+use crate::smtp_server::greylist::parse_network;
+use anyhow::{anyhow, Result};
+use ipnet::IpNet;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The shortest v1 header is `PROXY UNKNOWN\r\n`, the longest 107 bytes.
+const V1_MIN_LEN: usize = 15;
+const V1_MAX_LEN: usize = 107;
+/// How long a trusted proxy may take to send the header.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// HAProxy PROXY protocol v1/v2. Only connections from `trusted` proxies are
+/// expected to send the header, everybody else connects directly.
+#[derive(Debug, Clone)]
+pub struct ProxyProtocol {
+    trusted: Vec<IpNet>,
+}
+
+enum Step {
+    Read(usize),
+    Done(Option<SocketAddr>),
+}
+
+impl ProxyProtocol {
+    pub fn new(trusted: Vec<IpNet>) -> Self {
+        ProxyProtocol { trusted }
+    }
+
+    /// Enabled per listener by `enable_var`, trusted proxies come from `PROXY_PROTOCOL_TRUSTED`.
+    pub fn from_env(enable_var: &str) -> Result<Option<Self>> {
+        if !matches!(config::var(enable_var).as_deref(), Ok("true") | Ok("1")) {
+            return Ok(None);
+        }
+        let trusted = config::var("PROXY_PROTOCOL_TRUSTED")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(parse_network)
+            .collect::<Result<Vec<_>>>()?;
+        if trusted.is_empty() {
+            return Err(anyhow!(
+                "`PROXY_PROTOCOL_TRUSTED` must be set when `{}` is enabled",
+                enable_var
+            ));
+        }
+        Ok(Some(ProxyProtocol::new(trusted)))
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Returns the original client address. The header is read without consuming
+    /// anything after it, so the stream can be handed to the protocol handler as is.
+    pub fn accept(&self, peer: SocketAddr, stream: &mut impl Read) -> io::Result<SocketAddr> {
+        if !self.is_trusted(peer.ip()) {
+            return Ok(peer);
+        }
+        let mut buf = Vec::with_capacity(V1_MAX_LEN);
+        loop {
+            match step(&buf)? {
+                Step::Read(n) => {
+                    let start = buf.len();
+                    buf.resize(start + n, 0);
+                    stream.read_exact(&mut buf[start..])?;
+                }
+                Step::Done(addr) => return Ok(addr.unwrap_or(peer)),
+            }
+        }
+    }
+
+    pub async fn accept_async(
+        &self,
+        peer: SocketAddr,
+        stream: &mut (impl AsyncRead + Unpin),
+    ) -> io::Result<SocketAddr> {
+        if !self.is_trusted(peer.ip()) {
+            return Ok(peer);
+        }
+        let mut buf = Vec::with_capacity(V1_MAX_LEN);
+        loop {
+            match step(&buf)? {
+                Step::Read(n) => {
+                    let start = buf.len();
+                    buf.resize(start + n, 0);
+                    stream.read_exact(&mut buf[start..]).await?;
+                }
+                Step::Done(addr) => return Ok(addr.unwrap_or(peer)),
+            }
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("proxy protocol: {}", msg),
+    )
+}
+
+/// Decides how many more bytes are needed, or parses the complete header.
+fn step(buf: &[u8]) -> io::Result<Step> {
+    if buf.len() < V1_MIN_LEN {
+        return Ok(Step::Read(V1_MIN_LEN - buf.len()));
+    }
+    if buf.starts_with(V2_SIGNATURE) {
+        if buf.len() < 16 {
+            return Ok(Step::Read(16 - buf.len()));
+        }
+        let total = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        if buf.len() < total {
+            return Ok(Step::Read(total - buf.len()));
+        }
+        return parse_v2(buf).map(Step::Done);
+    }
+    if buf.starts_with(b"PROXY ") {
+        if buf.ends_with(b"\r\n") {
+            return parse_v1(buf).map(Step::Done);
+        }
+        if buf.len() >= V1_MAX_LEN {
+            return Err(invalid("v1 header too long"));
+        }
+        return Ok(Step::Read(1));
+    }
+    Err(invalid("missing header"))
+}
+
+/// `PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n`
+fn parse_v1(buf: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(&buf[..buf.len() - 2]).map_err(|_| invalid("not utf8"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("bad source address"))?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(invalid("address family mismatch"));
+            }
+            let port: u16 = src_port.parse().map_err(|_| invalid("bad source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed v1 header")),
+    }
+}
+
+fn parse_v2(buf: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let version_command = buf[12];
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL: health checks from the proxy itself
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unsupported command")),
+    }
+    let addr = &buf[16..];
+    match buf[13] >> 4 {
+        1 if addr.len() >= 12 => {
+            let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+            let port = u16::from_be_bytes([addr[8], addr[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        2 if addr.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addr[..16]);
+            let port = u16::from_be_bytes([addr[32], addr[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        1 | 2 => Err(invalid("truncated address")),
+        // AF_UNIX and AF_UNSPEC carry no usable client ip
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy() -> ProxyProtocol {
+        ProxyProtocol::new(vec![parse_network("10.0.0.0/8").unwrap()])
+    }
+
+    fn accept(peer: &str, data: &[u8]) -> (io::Result<SocketAddr>, Vec<u8>) {
+        let mut reader = data;
+        let ret = proxy().accept(peer.parse().unwrap(), &mut reader);
+        (ret, reader.to_vec())
+    }
+
+    #[test]
+    fn test_v1() {
+        let (addr, rest) = accept(
+            "10.0.0.1:1234",
+            b"PROXY TCP4 1.2.3.4 10.0.0.2 56324 25\r\nEHLO mx\r\n",
+        );
+        assert_eq!(addr.unwrap(), "1.2.3.4:56324".parse().unwrap());
+        assert_eq!(rest, b"EHLO mx\r\n");
+
+        let (addr, _) = accept("10.0.0.1:1234", b"PROXY TCP6 2001:db8::1 ::1 443 25\r\n");
+        assert_eq!(addr.unwrap(), "[2001:db8::1]:443".parse().unwrap());
+
+        let (addr, rest) = accept("10.0.0.1:1234", b"PROXY UNKNOWN\r\nEHLO mx\r\n");
+        assert_eq!(addr.unwrap(), "10.0.0.1:1234".parse().unwrap());
+        assert_eq!(rest, b"EHLO mx\r\n");
+
+        let (addr, _) = accept("10.0.0.1:1234", b"PROXY TCP4 2001:db8::1 ::1 443 25\r\n");
+        assert!(addr.is_err());
+        let (addr, _) = accept("10.0.0.1:1234", b"EHLO mx.example.com\r\n");
+        assert!(addr.is_err());
+
+        // untrusted peers are taken as is, even if they send a header
+        let (addr, rest) = accept("1.1.1.1:1234", b"PROXY TCP4 1.2.3.4 10.0.0.2 1 25\r\n");
+        assert_eq!(addr.unwrap(), "1.1.1.1:1234".parse().unwrap());
+        assert_eq!(rest, b"PROXY TCP4 1.2.3.4 10.0.0.2 1 25\r\n");
+    }
+
+    #[test]
+    fn test_v2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[
+            0x21, 0x11, 0, 12, 1, 2, 3, 4, 10, 0, 0, 2, 0xdc, 0x04, 0, 25,
+        ]);
+        data.extend_from_slice(b"EHLO mx\r\n");
+        let (addr, rest) = accept("10.0.0.1:1234", &data);
+        assert_eq!(addr.unwrap(), "1.2.3.4:56324".parse().unwrap());
+        assert_eq!(rest, b"EHLO mx\r\n");
+
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        let (addr, rest) = accept("10.0.0.1:1234", &data);
+        assert_eq!(addr.unwrap(), "10.0.0.1:1234".parse().unwrap());
+        assert!(rest.is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Shared between the servers and the supervisor in `main`. Once triggered the
+/// listeners stop accepting and no new mail is taken, deliveries already running
+/// are waited for.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<(Mutex<State>, Condvar)>,
+}
+
+#[derive(Default)]
+struct State {
+    stopping: bool,
+    deliveries: usize,
+}
+
+/// Marks a delivery as in flight until dropped.
+pub struct DeliveryGuard {
+    shutdown: Shutdown,
+}
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        let (state, cond) = &*self.inner;
+        state.lock().unwrap().stopping = true;
+        cond.notify_all();
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.inner.0.lock().unwrap().stopping
+    }
+
+    /// Sleeps up to `timeout`, returns true as soon as shutdown is triggered.
+    pub fn wait_stopping(&self, timeout: Duration) -> bool {
+        let (state, cond) = &*self.inner;
+        let state = state.lock().unwrap();
+        cond.wait_timeout_while(state, timeout, |state| !state.stopping)
+            .unwrap()
+            .0
+            .stopping
+    }
+
+    pub fn delivery(&self) -> DeliveryGuard {
+        self.inner.0.lock().unwrap().deliveries += 1;
+        DeliveryGuard {
+            shutdown: self.clone(),
+        }
+    }
+
+    /// Returns false if deliveries were still running when `timeout` passed.
+    pub fn wait_deliveries(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let (state, cond) = &*self.inner;
+        let mut state = state.lock().unwrap();
+        while state.deliveries > 0 {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return false;
+            }
+            state = cond.wait_timeout(state, left).unwrap().0;
+        }
+        true
+    }
+}
+
+impl Drop for DeliveryGuard {
+    fn drop(&mut self) {
+        let (state, cond) = &*self.shutdown.inner;
+        state.lock().unwrap().deliveries -= 1;
+        cond.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_wait_deliveries() {
+        let shutdown = Shutdown::default();
+        assert!(!shutdown.is_stopping());
+        assert!(!shutdown.wait_stopping(Duration::from_millis(1)));
+        let guard = shutdown.delivery();
+        shutdown.trigger();
+        assert!(shutdown.wait_stopping(Duration::from_secs(10)));
+        assert!(shutdown.is_stopping());
+        assert!(!shutdown.wait_deliveries(Duration::from_millis(10)));
+
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(guard);
+        });
+        assert!(shutdown.wait_deliveries(Duration::from_secs(5)));
+        handle.join().unwrap();
+    }
+}
@@ -1,165 +1,1095 @@
-mod mail;
+pub mod auth;
+pub mod dnsbl;
+pub mod envelope;
+pub mod greylist;
+pub mod lmtp;
+pub mod mail;
+pub mod rate_limit;
+pub mod spool;
 
-use crate::bot_server::feishu_client::{Client, FileType};
+use crate::bot_server::feishu_client::{can_upload, Client, FileType, MessageType};
+use crate::bot_server::mail_url::attachment_url;
 use crate::bot_server::MailUrlGen;
-use crate::smtp_server::mail::get_data_from_mail;
-use crate::store::Store;
-use anyhow::{anyhow, Result};
-use log::{debug, error, info};
-use mailin_embedded::{Handler, Response, Server};
-use std::net::IpAddr;
-use std::{io, vec};
+use crate::proxy_protocol::{ProxyProtocol, HEADER_TIMEOUT};
+use crate::shutdown::Shutdown;
+use crate::smtp_server::dnsbl::{Dnsbl, Listing};
+use crate::smtp_server::envelope::MailEnvelope;
+use crate::smtp_server::greylist::Greylist;
+use crate::smtp_server::lmtp::{LmtpAddr, LmtpListener};
+use crate::smtp_server::mail::{
+    format_attachment_links, format_text, get_data_from_mail, parse_subject, read_headers,
+};
+use crate::smtp_server::rate_limit::{ChatLimit, RateLimits};
+use crate::smtp_server::spool::{Spool, SpoolConfig};
+use crate::store::{RenderMode, Store};
+use crate::{config, listen, logging, metrics, telemetry, tls};
+use anyhow::{anyhow, Context, Result};
+use log::{debug, error, info, warn};
+use mailin::{Action, AuthMechanism, Session, SessionBuilder};
+use mailin_embedded::{Handler, Response};
+use opentelemetry::KeyValue;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fmt, io, thread, vec};
 use uuid::Uuid;
 
+const DEFAULT_MAX_SESSIONS: usize = 100;
+/// How long a session may stay silent, RFC 5321 4.5.3.2 asks for at least 5 minutes.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Held while a spooled mail is read back and parsed for forwarding.
+static SPOOLED_PARSE: Mutex<()> = Mutex::new(());
+
+pub struct SmtpConfig {
+    pub rate_limits: RateLimits,
+    pub greylist: Option<Greylist>,
+    pub dnsbl: Option<Dnsbl>,
+    pub spool: SpoolConfig,
+    /// Set `SMTP_ENABLED=false` to only accept mail over LMTP.
+    pub smtp_enabled: bool,
+    pub listen: Vec<String>,
+    /// Implicit TLS listeners, usually port 465.
+    pub tls_listen: Vec<String>,
+    pub tls: Option<Arc<ServerConfig>>,
+    pub lmtp: Option<LmtpAddr>,
+    /// Octal permissions of the LMTP Unix socket.
+    pub lmtp_socket_mode: Option<u32>,
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Concurrent sessions over all SMTP listeners, more connections get 421.
+    pub max_sessions: usize,
+}
+
+impl SmtpConfig {
+    pub fn from_env() -> Result<Self> {
+        let smtp_enabled = !matches!(
+            config::var("SMTP_ENABLED").as_deref(),
+            Ok("false") | Ok("0")
+        );
+        let lmtp = match config::var("LMTP_LISTEN") {
+            Ok(addr) if !addr.is_empty() => Some(addr.parse()?),
+            _ => None,
+        };
+        let lmtp_socket_mode = match config::var("LMTP_SOCKET_MODE") {
+            Ok(mode) if !mode.is_empty() => Some(lmtp::parse_socket_mode(&mode)?),
+            _ => None,
+        };
+        let tls_listen = listen::addrs_from_env("SMTPS_LISTEN", "");
+        let tls = match (config::var("SMTP_TLS_CERT"), config::var("SMTP_TLS_KEY")) {
+            (Ok(cert), Ok(key)) => {
+                Some(tls::load_server_config(Path::new(&cert), Path::new(&key))?)
+            }
+            _ if !tls_listen.is_empty() => {
+                return Err(anyhow!(
+                    "`SMTP_TLS_CERT` and `SMTP_TLS_KEY` must be set for `SMTPS_LISTEN`"
+                ))
+            }
+            _ => None,
+        };
+        let max_sessions = match config::var("SMTP_MAX_SESSIONS") {
+            Ok(n) => n.parse().context("SMTP_MAX_SESSIONS")?,
+            Err(_) => DEFAULT_MAX_SESSIONS,
+        };
+        Ok(SmtpConfig {
+            rate_limits: RateLimits::from_env()?,
+            greylist: Greylist::from_env()?,
+            dnsbl: Dnsbl::from_env()?,
+            spool: SpoolConfig::from_env()?,
+            smtp_enabled,
+            listen: listen::addrs_from_env("SMTP_LISTEN", "0.0.0.0:25"),
+            tls_listen,
+            tls,
+            lmtp,
+            lmtp_socket_mode,
+            proxy_protocol: ProxyProtocol::from_env("SMTP_PROXY_PROTOCOL")?,
+            max_sessions,
+        })
+    }
+}
+
 #[derive(Clone)]
 struct MailHandler {
     mail_url_gen: MailUrlGen,
     store: Store,
     client: Client,
-    rcpts: Vec<String>,
-    body: Vec<u8>,
+    rate_limits: RateLimits,
+    greylist: Option<Greylist>,
+    dnsbl: Option<Dnsbl>,
+    blocked: Option<Listing>,
+    /// Result of the per-IP connection check, made at the first HELO/EHLO of the
+    /// session so a repeated greeting (e.g. after STARTTLS) costs no extra token.
+    conn_allowed: Option<bool>,
+    envelope: MailEnvelope,
+    body: Spool,
+    /// Assigned at `data_start`, used as storage id and in every log line of the mail.
+    mail_id: String,
     url: String,
+    /// Off for LMTP, the MTA in front already checked the client and every
+    /// connection comes from the MTA's own address.
+    ip_checks: bool,
+    /// The chat of the SMTP credential, the only recipient an authenticated session may use.
+    auth_chat: Option<String>,
+    shutdown: Shutdown,
 }
 
 impl MailHandler {
-    pub fn new(client: Client, store: Store, mail_url_gen: MailUrlGen) -> Self {
+    pub fn new(
+        client: Client,
+        store: Store,
+        mail_url_gen: MailUrlGen,
+        config: &SmtpConfig,
+        shutdown: Shutdown,
+    ) -> Self {
         MailHandler {
             store,
             client,
             mail_url_gen,
-            body: Vec::new(),
-            rcpts: Vec::new(),
+            rate_limits: config.rate_limits.clone(),
+            greylist: config.greylist.clone(),
+            dnsbl: config.dnsbl.clone(),
+            blocked: None,
+            conn_allowed: None,
+            envelope: MailEnvelope::default(),
+            body: Spool::new(config.spool.clone()),
+            mail_id: "".to_string(),
             url: "".to_string(),
+            ip_checks: true,
+            auth_chat: None,
+            shutdown,
         }
     }
 
-    pub fn store(&mut self) {
-        let id = Uuid::new_v4().to_string();
-        if let Err(e) = self.store.save_mail(&id, &self.body) {
-            error!("store mail error: {}", e)
-        } else {
-            self.url = self.mail_url_gen.gen_url(&id);
-            debug!("store mail: {}", &self.url);
-        }
+    pub fn store(&mut self) -> Result<()> {
+        let id = self.mail_id.clone();
+        let received = self.envelope.received_header(self.store.mail_domain(), &id);
+        let len = received.len() + self.body.len();
+        let subject = parse_subject(&read_headers(self.body.reader()?)?).unwrap_or_else(|e| {
+            warn!("parse subject error: {}", e);
+            String::new()
+        });
+        let body = self.body.reader()?;
+        self.store.save_mail_from_reader(
+            &id,
+            &self.envelope,
+            &subject,
+            len,
+            &mut received.as_bytes().chain(body),
+        )?;
+        self.url = self.mail_url_gen.gen_url(&id);
+        debug!(
+            "store mail, size: {}, spooled: {}",
+            len,
+            self.body.is_spooled()
+        );
+        Ok(())
     }
 
     fn clear(&mut self) {
-        self.rcpts.clear();
+        self.envelope.reset_transaction();
         self.body.clear();
+        self.mail_id.clear();
         self.url.clear();
+        logging::set_mail_id(None);
     }
 
-    fn notify(&mut self) -> Result<()> {
-        let mail_content = match get_data_from_mail(&self.body) {
+    /// Stores the mail and forwards it to the recipient chats, with one result per
+    /// recipient. Fails without forwarding anything if the mail couldn't be stored,
+    /// so the sender keeps it and retries.
+    fn deliver(&mut self) -> Result<Vec<(String, Result<()>)>> {
+        let _delivery = self.shutdown.delivery();
+        let _span = telemetry::span(
+            "mail.deliver",
+            vec![
+                KeyValue::new("mail.id", self.mail_id.clone()),
+                KeyValue::new("mail.size", self.body.len() as i64),
+                KeyValue::new("mail.rcpts", self.envelope.rcpts.len() as i64),
+            ],
+        );
+        metrics::DELIVERIES_IN_FLIGHT.inc();
+        let results = match self.store() {
+            Ok(()) => Ok(self.notify().unwrap_or_else(|e| {
+                error!("notify error: {}", e);
+                telemetry::set_error(&e);
+                let unparsable = e.is::<UnparsableMail>();
+                self.envelope
+                    .rcpts
+                    .iter()
+                    .map(|rcpt| {
+                        let e = if unparsable {
+                            anyhow!(UnparsableMail)
+                        } else {
+                            anyhow!("{}", e)
+                        };
+                        (rcpt.clone(), Err(e))
+                    })
+                    .collect()
+            })),
             Err(e) => {
-                error!("get text from mail error: {}", e);
-                return Err(e);
+                error!("store mail error: {}", e);
+                telemetry::set_error(&e);
+                Err(e)
             }
-            Ok(body) => body,
         };
+        self.clear();
+        metrics::DELIVERIES_IN_FLIGHT.dec();
+        results
+    }
+
+    fn notify(&mut self) -> Result<Vec<(String, Result<()>)>> {
+        if !self.body.is_spooled() {
+            let body = self.body.read_all()?;
+            return notify(&self.client, &self.store, &self.envelope, &body, &self.url);
+        }
+        // the parser needs the whole mail in memory, so spooled mails take turns and
+        // concurrent sessions never hold more than one of them at a time
+        let rendered = {
+            let _turn = SPOOLED_PARSE.lock().unwrap_or_else(|e| e.into_inner());
+            let body = self.body.read_all()?;
+            render_mail(&body, &self.envelope, &self.url)?
+        };
+        forward(
+            &self.client,
+            &self.store,
+            &self.envelope,
+            rendered,
+            &self.url,
+        )
+    }
+
+    fn auth(&mut self, username: &str, password: &str) -> Response {
+        match auth::authenticate(&self.store, username, password) {
+            Ok(Some(chat_id)) => {
+                info!("auth ok: {}, chat: {}", username, chat_id);
+                self.envelope.auth = Some(username.to_string());
+                self.auth_chat = Some(chat_id);
+                mailin_embedded::response::AUTH_OK
+            }
+            Ok(None) => {
+                warn!("auth failed: {}", username);
+                mailin_embedded::response::INVALID_CREDENTIALS
+            }
+            Err(e) => {
+                error!("auth error: {}", e);
+                mailin_embedded::response::INTERNAL_ERROR
+            }
+        }
+    }
 
-        let mut file_ids = vec![];
-        for (filename, data) in mail_content.files {
-            let file_id = self.client.create_file(FileType::Stream, filename, &data)?;
-            file_ids.push(file_id);
+    fn notify_rate_limited(&self, chat_id: &str) {
+        let text = "mail rate limited: too many mails were sent to this group, \
+            further mails are rejected until the rate drops"
+            .to_string();
+        if let Err(e) = self.client.send_text_message(chat_id.to_string(), text) {
+            error!(
+                "send rate limited notice error, chat_id: {}, msg: {}",
+                chat_id, e
+            );
         }
+    }
+}
 
-        info!("file ids: {:?}", file_ids);
-        let body = format!("{}\n\nraw mail: {}", &mail_content.text, &self.url);
+/// What a mail turns into in each chat: the text message content, then one
+/// uploaded file message per attachment.
+pub struct Rendered {
+    pub text: Value,
+    pub files: Vec<(String, Vec<u8>)>,
+}
 
-        for rcpt in &self.rcpts {
-            if let Some(name) = rcpt.split('@').next() {
-                if self.store.exist_chat(name) {
-                    debug!("notify {}", rcpt);
-                    // send text message
-                    let ret = self
-                        .client
-                        .send_text_message(name.to_string(), body.to_string());
-                    if let Err(e) = ret {
-                        error!(
-                            "send text message error, chat_id: {}, body: {}, msg: {}",
-                            name, body, e
-                        );
-                    }
-                    // send file message
-                    for file_id in &file_ids {
-                        let ret = self
-                            .client
-                            .send_file_message(name.to_string(), file_id.to_string());
-                        if let Err(e) = ret {
-                            error!(
-                                "send file message error, chat_id: {}, file_id: {}, msg: {}",
-                                name, file_id, e
-                            );
-                        }
-                    }
-                }
+/// Runs the same formatting as delivery without touching the network.
+pub fn render(body: &[u8], envelope: &MailEnvelope, url: &str) -> Result<Rendered> {
+    let _span = telemetry::span("mail.parse", vec![]);
+    let mail_content = get_data_from_mail(body).inspect_err(|e| telemetry::set_error(e))?;
+    telemetry::set_attribute(KeyValue::new(
+        "mail.attachments",
+        mail_content.files.len() as i64,
+    ));
+    let text = format_text(&mail_content, envelope, url);
+    Ok(Rendered {
+        text: json!({ "text": text }),
+        files: mail_content.files,
+    })
+}
+
+/// Attachments that can't be uploaded are linked from the text message, as `(filename, url)`.
+pub fn add_attachment_links(text: &mut Value, links: &[(String, String)]) {
+    if let Some(Value::String(text)) = text.get_mut("text") {
+        text.push_str(&format_attachment_links(links));
+    }
+}
+
+/// Forwards a stored mail to its recipient chats, with one result per recipient.
+fn notify(
+    client: &Client,
+    store: &Store,
+    envelope: &MailEnvelope,
+    body: &[u8],
+    url: &str,
+) -> Result<Vec<(String, Result<()>)>> {
+    let rendered = render_mail(body, envelope, url)?;
+    forward(client, store, envelope, rendered, url)
+}
+
+/// A stored mail that can't be parsed, retrying the delivery won't change that.
+#[derive(Debug)]
+pub struct UnparsableMail;
+
+impl fmt::Display for UnparsableMail {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("mail could not be parsed")
+    }
+}
+
+impl std::error::Error for UnparsableMail {}
+
+fn render_mail(body: &[u8], envelope: &MailEnvelope, url: &str) -> Result<Rendered> {
+    render(body, envelope, url)
+        .inspect_err(|e| {
+            error!("get text from mail error: {}", e);
+            metrics::PARSE_FAILURES.inc();
+        })
+        .context(UnparsableMail)
+}
+
+/// Sends a rendered mail to every recipient chat, following the chat settings.
+fn forward(
+    client: &Client,
+    store: &Store,
+    envelope: &MailEnvelope,
+    rendered: Rendered,
+    url: &str,
+) -> Result<Vec<(String, Result<()>)>> {
+    // indexed by recipient, LMTP answers one line per recipient in RCPT order
+    let mut results: Vec<Option<Result<()>>> = envelope.rcpts.iter().map(|_| None).collect();
+    let mut targets = vec![];
+    for (i, rcpt) in envelope.rcpts.iter().enumerate() {
+        let Some(name) = store.rcpt_chat(rcpt)? else {
+            results[i] = Some(Err(anyhow!("unknown chat: {}", rcpt)));
+            continue;
+        };
+        let Some(settings) = store.chat_settings(&name)? else {
+            results[i] = Some(Err(anyhow!("unknown chat: {}", name)));
+            continue;
+        };
+        // the mail stays readable on the web and through the API
+        if settings.muted {
+            info!("chat {} is muted, not forwarding", name);
+            results[i] = Some(Ok(()));
+            continue;
+        }
+        if settings.blocks(&envelope.mail_from) {
+            info!(
+                "chat {} blocks {}, not forwarding",
+                name, envelope.mail_from
+            );
+            results[i] = Some(Ok(()));
+            continue;
+        }
+        targets.push((i, rcpt, name, settings.render_mode));
+    }
+
+    let mut text = rendered.text;
+    let all_links: Vec<_> = rendered
+        .files
+        .iter()
+        .enumerate()
+        .map(|(n, (filename, _))| (filename.clone(), attachment_url(url, n)))
+        .collect();
+    let mut links_text = text.clone();
+    add_attachment_links(&mut links_text, &all_links);
+    // nothing to upload when every chat only gets links
+    let files = if targets
+        .iter()
+        .any(|(_, _, _, mode)| *mode == RenderMode::Full)
+    {
+        rendered.files
+    } else {
+        vec![]
+    };
+    let mut file_ids = vec![];
+    let mut links = vec![];
+    for (n, (filename, data)) in files.into_iter().enumerate() {
+        if !can_upload(data.len()) {
+            info!(
+                "attachment {} has {} bytes, sending a link instead",
+                filename,
+                data.len()
+            );
+            links.push((filename, attachment_url(url, n)));
+            continue;
+        }
+        match client.create_file(FileType::Stream, filename.clone(), &data) {
+            Ok(file_id) => file_ids.push(file_id),
+            Err(e) => {
+                warn!(
+                    "upload attachment {} error, sending a link instead: {}",
+                    filename, e
+                );
+                links.push((filename, attachment_url(url, n)));
             }
         }
-        return Ok(());
     }
+    add_attachment_links(&mut text, &links);
+
+    info!("file ids: {:?}", file_ids);
+
+    for (i, rcpt, name, mode) in targets {
+        debug!("notify {}", rcpt);
+        let (text, file_ids) = match mode {
+            RenderMode::Full => (&text, &file_ids[..]),
+            RenderMode::Links => (&links_text, &[][..]),
+        };
+        // send text message
+        let ret = client.send_message(name.to_string(), MessageType::Text, text.clone());
+        if let Err(e) = &ret {
+            error!("send text message error, chat_id: {}, msg: {}", name, e);
+        }
+        // send file message
+        for file_id in file_ids {
+            let ret = client.send_file_message(name.to_string(), file_id.to_string());
+            if let Err(e) = ret {
+                error!(
+                    "send file message error, chat_id: {}, file_id: {}, msg: {}",
+                    name, file_id, e
+                );
+            }
+        }
+        results[i] = Some(ret);
+    }
+    Ok(envelope
+        .rcpts
+        .iter()
+        .cloned()
+        .zip(
+            results
+                .into_iter()
+                .map(|ret| ret.expect("every recipient has a result")),
+        )
+        .collect())
+}
+
+/// Forwards a stored mail again, e.g. after the Feishu API was down.
+pub fn redeliver(
+    client: &Client,
+    store: &Store,
+    mail_url_gen: &MailUrlGen,
+    id: &str,
+) -> Result<Vec<(String, Result<()>)>> {
+    let body = store
+        .get_mail(id)?
+        .ok_or_else(|| anyhow!("mail not found: {}", id))?;
+    let envelope = store
+        .get_mail_envelope(id)?
+        .ok_or_else(|| anyhow!("mail not found: {}", id))?;
+    logging::set_mail_id(Some(id));
+    if envelope.rcpts.is_empty() {
+        return Err(anyhow!("mail {} has no recorded recipients", id));
+    }
+    notify(client, store, &envelope, &body, &mail_url_gen.gen_url(id))
 }
 
 impl Handler for MailHandler {
-    fn helo(&mut self, ip: IpAddr, _domain: &str) -> Response {
+    fn helo(&mut self, ip: IpAddr, domain: &str) -> Response {
         info!("helo from {}", ip);
+        self.envelope.client_ip = Some(ip);
+        self.envelope.helo = domain.to_string();
+        if !self.ip_checks {
+            return mailin_embedded::response::OK;
+        }
+        if let Some(allowed) = self.conn_allowed {
+            return if allowed {
+                mailin_embedded::response::OK
+            } else {
+                mailin_embedded::response::NO_SERVICE
+            };
+        }
+        let allowed = self.rate_limits.allow_connection(ip);
+        self.conn_allowed = Some(allowed);
+        if !allowed {
+            warn!("connection rate limited: {}", ip);
+            metrics::reject("connection_rate");
+            return mailin_embedded::response::NO_SERVICE;
+        }
+        if let Some(dnsbl) = &self.dnsbl {
+            self.blocked = dnsbl.blocked(ip);
+            if let Some(listing) = &self.blocked {
+                warn!("dnsbl listed: {}, zones: {:?}", ip, &listing.zones);
+            }
+        }
         mailin_embedded::response::OK
     }
 
-    fn mail(&mut self, _ip: IpAddr, _domain: &str, _from: &str) -> Response {
+    fn mail(&mut self, ip: IpAddr, _domain: &str, from: &str) -> Response {
+        if self.shutdown.is_stopping() {
+            metrics::reject("shutting_down");
+            return Response::custom(421, "4.3.2 Service shutting down".to_string());
+        }
+        let authenticated = self.envelope.auth.is_some();
+        if let (Some(listing), false) = (&self.blocked, authenticated) {
+            metrics::reject("dnsbl");
+            return Response::custom(
+                554,
+                format!(
+                    "5.7.1 Client host {} blocked using {}",
+                    ip,
+                    listing.zones.join(", ")
+                ),
+            );
+        }
+        if self.ip_checks && !self.rate_limits.allow_mail_from(ip) {
+            warn!("mail rate limited: {}", ip);
+            metrics::reject("mail_rate");
+            return Response::custom(451, "Too many messages, try again later".to_string());
+        }
+        self.envelope.client_ip = Some(ip);
+        self.envelope.mail_from = from.to_string();
         mailin_embedded::response::OK
     }
 
     fn rcpt(&mut self, to: &str) -> Response {
         info!("rcpt to {}", to);
-        self.rcpts.push(to.to_string());
+        let chat = match self.store.rcpt_chat(to) {
+            Ok(chat) => chat,
+            Err(e) => {
+                error!("resolve recipient {} error: {}", to, e);
+                return mailin_embedded::response::INTERNAL_ERROR;
+            }
+        };
+        // the credential skips the spam checks, so it must not reach other chats
+        if let Some(chat_id) = &self.auth_chat {
+            if chat.as_ref() != Some(chat_id) {
+                warn!("{} is not the chat of {:?}", to, self.envelope.auth);
+                metrics::reject("auth_recipient");
+                return Response::custom(
+                    550,
+                    format!("5.7.1 <{}> is not the chat of this account", to),
+                );
+            }
+        }
+        let Some(name) = chat else {
+            info!("unknown chat: {}", to);
+            metrics::reject("unknown_chat");
+            return Response::custom(550, format!("5.1.1 <{}> unknown chat", to));
+        };
+        if let (Some(greylist), Some(ip), None, true) = (
+            &self.greylist,
+            self.envelope.client_ip,
+            &self.envelope.auth,
+            self.ip_checks,
+        ) {
+            let from = &self.envelope.mail_from;
+            match greylist.check(&self.store, ip, from, to) {
+                Ok(true) => {}
+                Ok(false) => {
+                    info!("greylisted: {} {} {}", ip, from, to);
+                    metrics::reject("greylisted");
+                    return Response::custom(
+                        451,
+                        "4.7.1 Greylisted, please try again later".to_string(),
+                    );
+                }
+                Err(e) => error!("greylist error: {}", e),
+            }
+        }
+        if let ChatLimit::Limited { first } = self.rate_limits.allow_mail_to_chat(&name) {
+            warn!("chat rate limited: {}", name);
+            metrics::reject("chat_rate");
+            if first {
+                self.notify_rate_limited(&name);
+            }
+            return Response::custom(
+                451,
+                "Too many messages for this recipient, try again later".to_string(),
+            );
+        }
+        self.envelope.rcpts.push(to.to_string());
         mailin_embedded::response::OK
     }
 
-    fn data_start(
-        &mut self,
-        _domain: &str,
-        _from: &str,
-        _is8bit: bool,
-        _to: &[String],
-    ) -> Response {
+    fn data_start(&mut self, domain: &str, from: &str, _is8bit: bool, to: &[String]) -> Response {
+        self.envelope.helo = domain.to_string();
+        self.envelope.mail_from = from.to_string();
+        if self.envelope.rcpts.is_empty() {
+            self.envelope.rcpts = to.to_vec();
+        }
+        self.envelope.received_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        self.mail_id = Uuid::new_v4().to_string();
+        logging::set_mail_id(Some(&self.mail_id));
+        info!("data from {} to {:?}", from, self.envelope.rcpts);
         mailin_embedded::response::OK
     }
 
     fn data(&mut self, buf: &[u8]) -> io::Result<()> {
-        self.body.extend_from_slice(buf);
-        Ok(())
+        self.body.write(buf)
     }
 
     fn data_end(&mut self) -> Response {
-        self.store();
-        if let Err(e) = self.notify() {
-            error!("notify error: {}", e);
+        if self.body.exceeded() {
+            warn!("mail exceeds max size {}, rejected", self.body.max_size());
+            metrics::reject("too_large");
+            self.clear();
+            return Response::custom(
+                552,
+                "5.3.4 Message size exceeds fixed maximum message size".to_string(),
+            );
+        }
+        metrics::MESSAGES_ACCEPTED.inc();
+        metrics::MESSAGE_SIZE.observe(self.body.len() as f64);
+        match self.deliver() {
+            Ok(results) => {
+                for (rcpt, ret) in results {
+                    if let Err(e) = ret {
+                        warn!("deliver to {} failed: {}", rcpt, e);
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("mail not stored: {}", e);
+                return Response::custom(451, "4.3.0 Mail not stored, try again later".to_string());
+            }
         }
-        self.clear();
         mailin_embedded::response::OK
     }
 
     fn auth_plain(
         &mut self,
         _authorization_id: &str,
-        _authentication_id: &str,
-        _password: &str,
+        authentication_id: &str,
+        password: &str,
     ) -> Response {
-        mailin_embedded::response::AUTH_OK
+        self.auth(authentication_id, password)
+    }
+
+    fn auth_login(&mut self, username: &str, password: &str) -> Response {
+        self.auth(username, password)
+    }
+}
+
+/// Sockets bound up front, so privileges can be dropped before serving.
+pub struct SmtpListeners {
+    smtp: Vec<TcpListener>,
+    smtps: Vec<TcpListener>,
+    lmtp: Option<LmtpListener>,
+}
+
+impl SmtpListeners {
+    pub fn bind(config: &SmtpConfig) -> Result<Self> {
+        let (smtp, smtps) = if config.smtp_enabled {
+            (
+                listen::tcp_listeners("smtp", &config.listen)?,
+                listen::tcp_listeners("smtps", &config.tls_listen)?,
+            )
+        } else {
+            (vec![], vec![])
+        };
+        let lmtp = match &config.lmtp {
+            Some(addr) => Some(LmtpListener::bind(addr, config.lmtp_socket_mode)?),
+            None => None,
+        };
+        Ok(SmtpListeners { smtp, smtps, lmtp })
+    }
+}
+
+pub fn serve(
+    client: Client,
+    store: Store,
+    mail_url_gen: MailUrlGen,
+    config: SmtpConfig,
+    listeners: SmtpListeners,
+    shutdown: Shutdown,
+) -> Result<()> {
+    let handler = MailHandler::new(client, store, mail_url_gen, &config, shutdown);
+    let lmtp = listeners.lmtp.map(|listener| {
+        let handler = handler.clone();
+        thread::spawn(move || lmtp::serve(handler, listener))
+    });
+
+    let plain_builder = Arc::new(session_builder(false));
+    let tls_builder = Arc::new(session_builder(true));
+    let plain = listeners
+        .smtp
+        .into_iter()
+        .map(|listener| (listener, plain_builder.clone(), None));
+    let implicit_tls = listeners
+        .smtps
+        .into_iter()
+        .map(|listener| (listener, tls_builder.clone(), config.tls.clone()));
+    let sessions = SessionSlots::new(config.max_sessions);
+    let mut servers = vec![];
+    for (listener, builder, tls) in plain.chain(implicit_tls) {
+        let handler = handler.clone();
+        let proxy_protocol = config.proxy_protocol.clone();
+        let sessions = sessions.clone();
+        servers.push(thread::spawn(move || {
+            serve_smtp(listener, builder, handler, proxy_protocol, tls, sessions)
+        }));
+    }
+    for server in servers {
+        server
+            .join()
+            .map_err(|_| anyhow!("smtp server panicked"))??;
+    }
+    if let Some(lmtp) = lmtp {
+        lmtp.join().map_err(|_| anyhow!("lmtp server panicked"))??;
+    }
+    Ok(())
+}
+
+/// AUTH is only offered on the implicit TLS listener, there is no STARTTLS to
+/// protect the password on the plain one.
+fn session_builder(tls: bool) -> SessionBuilder {
+    let mut builder = SessionBuilder::new("Mailhook SMTP Server");
+    if tls {
+        builder
+            .enable_auth(AuthMechanism::Plain)
+            .enable_auth(AuthMechanism::Login);
     }
+    builder
+}
+
+/// Counts the running SMTP sessions, each one holds a thread until it ends.
+#[derive(Clone)]
+struct SessionSlots {
+    active: Arc<AtomicUsize>,
+    max: usize,
 }
 
-pub fn serve(client: Client, store: Store, mail_url_gen: MailUrlGen) -> Result<()> {
-    let handler = MailHandler::new(client, store, mail_url_gen);
-    let mut server = Server::new(handler);
+/// Frees its slot when the session ends.
+struct SessionSlot(Arc<AtomicUsize>);
 
-    server
-        .with_ssl(mailin_embedded::SslConfig::None)
-        .map_err(|e| anyhow!("{}", e))?
-        .with_name("Mailhook SMTP Server")
-        .with_addr("0.0.0.0:25")
-        .map_err(|e| anyhow!("{}", e))?;
-    server.serve().map_err(|e| anyhow!("{}", e))?;
+impl SessionSlots {
+    fn new(max: usize) -> Self {
+        SessionSlots {
+            active: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    fn acquire(&self) -> Option<SessionSlot> {
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < self.max).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| SessionSlot(self.active.clone()))
+    }
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn serve_smtp(
+    listener: TcpListener,
+    builder: Arc<SessionBuilder>,
+    handler: MailHandler,
+    proxy_protocol: Option<ProxyProtocol>,
+    tls: Option<Arc<ServerConfig>>,
+    sessions: SessionSlots,
+) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    let shutdown = handler.shutdown.clone();
+    let name = if tls.is_some() { "smtps" } else { "smtp" };
+    listen::accept_loop(
+        name,
+        &shutdown,
+        || listener.accept(),
+        |(mut stream, peer)| {
+            let Some(slot) = sessions.acquire() else {
+                warn!("too many smtp sessions, refusing {}", peer);
+                metrics::reject("too_many_sessions");
+                // an implicit TLS client can't read a plain reply, it is just closed
+                if tls.is_none() {
+                    let _ = stream.set_nonblocking(false);
+                    let _ = stream.set_write_timeout(Some(HEADER_TIMEOUT));
+                    let _ =
+                        stream.write_all(b"421 4.3.2 Too many connections, try again later\r\n");
+                }
+                return;
+            };
+            let handler = handler.clone();
+            let builder = builder.clone();
+            let proxy_protocol = proxy_protocol.clone();
+            let tls = tls.clone();
+            thread::spawn(move || {
+                let _slot = slot;
+                if let Err(e) = smtp_session(&builder, handler, proxy_protocol, tls, stream) {
+                    error!("smtp session error: {}", e);
+                }
+            });
+        },
+    );
     Ok(())
 }
+
+fn smtp_session(
+    builder: &SessionBuilder,
+    mut handler: MailHandler,
+    proxy_protocol: Option<ProxyProtocol>,
+    tls: Option<Arc<ServerConfig>>,
+    mut stream: TcpStream,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    let mut peer = stream.peer_addr()?;
+    if let Some(proxy_protocol) = proxy_protocol {
+        stream.set_read_timeout(Some(HEADER_TIMEOUT))?;
+        peer = proxy_protocol.accept(peer, &mut stream)?;
+    }
+    // idle clients must not hold their thread and session slot forever
+    stream.set_read_timeout(Some(SESSION_TIMEOUT))?;
+    stream.set_write_timeout(Some(SESSION_TIMEOUT))?;
+    debug!("smtp connection from {}, tls: {}", peer, tls.is_some());
+    let _span = telemetry::span(
+        "smtp.session",
+        vec![
+            KeyValue::new("net.peer.ip", peer.ip().to_string()),
+            KeyValue::new("smtp.tls", tls.is_some()),
+        ],
+    );
+    let max_size = handler.body.max_size();
+    match tls {
+        None => run_session(builder.build(peer.ip(), handler), stream, max_size),
+        Some(tls) => {
+            handler.envelope.tls = true;
+            let conn = ServerConnection::new(tls).map_err(io::Error::other)?;
+            run_session(
+                builder.build(peer.ip(), handler),
+                StreamOwned::new(conn, stream),
+                max_size,
+            )
+        }
+    }
+}
+
+/// Longest command line accepted, RFC 5321 allows 512 bytes plus extension parameters.
+const MAX_COMMAND_LINE: u64 = 1000;
+
+/// Reads a line of at most `limit` bytes into `line`, returns false at end of stream.
+fn read_line(reader: &mut impl BufRead, line: &mut Vec<u8>, limit: u64) -> io::Result<bool> {
+    line.clear();
+    Ok(reader.take(limit).read_until(b'\n', line)? > 0)
+}
+
+/// Whether `read_line` stopped at the limit before the end of the line.
+fn line_too_long(line: &[u8], limit: u64) -> bool {
+    !line.ends_with(b"\n") && line.len() as u64 >= limit
+}
+
+/// Splits the `SIZE=` parameter (RFC 1870) off a `MAIL FROM:` command, since mailin
+/// doesn't know it. The size is `None` if it isn't a number.
+fn split_size_param(line: &str) -> Option<(String, Option<u64>)> {
+    if !line.get(..10)?.eq_ignore_ascii_case("MAIL FROM:") {
+        return None;
+    }
+    let mut size = None;
+    let mut words = vec![];
+    for word in line.trim_end_matches(['\r', '\n']).split(' ') {
+        match word.get(..5) {
+            Some(key) if key.eq_ignore_ascii_case("SIZE=") => size = Some(word[5..].parse().ok()),
+            _ => words.push(word),
+        }
+    }
+    Some((format!("{}\r\n", words.join(" ")), size?))
+}
+
+/// Checks a declared `SIZE=` against the limit before any data is sent.
+fn check_size_param(size: Option<u64>, max_size: usize) -> Option<Response> {
+    match size {
+        None => Some(Response::custom(
+            501,
+            "5.5.4 Invalid SIZE parameter".to_string(),
+        )),
+        Some(size) if size > max_size as u64 => Some(Response::custom(
+            552,
+            "5.3.4 Message size exceeds fixed maximum message size".to_string(),
+        )),
+        Some(_) => None,
+    }
+}
+
+/// Appends `SIZE <max_size>` to the EHLO reply written by mailin.
+fn ehlo_with_size(response: &Response, max_size: usize) -> io::Result<Vec<u8>> {
+    let mut reply = vec![];
+    response.write_to(&mut reply)?;
+    let last = reply[..reply.len().saturating_sub(2)]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+    if reply.get(last + 3) == Some(&b' ') {
+        reply[last + 3] = b'-';
+    }
+    reply.extend_from_slice(format!("250 SIZE {}\r\n", max_size).as_bytes());
+    Ok(reply)
+}
+
+fn run_session(
+    mut session: Session<MailHandler>,
+    stream: impl Read + Write,
+    max_size: usize,
+) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    session.greeting().write_to(stream.get_mut())?;
+    let mut line = Vec::new();
+    let mut in_data = false;
+    loop {
+        // a single data line can't be longer than the whole message
+        let limit = if in_data {
+            max_size as u64 + 2
+        } else {
+            MAX_COMMAND_LINE
+        };
+        if !read_line(&mut stream, &mut line, limit)? {
+            return Ok(());
+        }
+        if line_too_long(&line, limit) {
+            let response = if in_data {
+                metrics::reject("too_large");
+                Response::custom(
+                    552,
+                    "5.3.4 Message size exceeds fixed maximum message size".to_string(),
+                )
+            } else {
+                Response::custom(500, "5.5.2 Line too long".to_string())
+            };
+            response.write_to(stream.get_mut())?;
+            return Ok(());
+        }
+        let mut command = None;
+        if !in_data {
+            if let Some((stripped, size)) = split_size_param(&String::from_utf8_lossy(&line)) {
+                if let Some(response) = check_size_param(size, max_size) {
+                    response.write_to(stream.get_mut())?;
+                    continue;
+                }
+                command = Some(stripped);
+            }
+        }
+        let response = session.process(command.as_ref().map_or(&line[..], |c| c.as_bytes()));
+        let is_ehlo = !in_data && line.len() >= 4 && line[..4].eq_ignore_ascii_case(b"EHLO");
+        in_data = response.code == 354 || (in_data && matches!(response.action, Action::NoReply));
+        match response.action {
+            Action::Close => {
+                response.write_to(stream.get_mut())?;
+                return Ok(());
+            }
+            Action::NoReply => {}
+            _ if is_ehlo && response.code == 250 => stream
+                .get_mut()
+                .write_all(&ehlo_with_size(&response, max_size)?)?,
+            _ => response.write_to(stream.get_mut())?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot_server::mail_url::LinkKey;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_session_slots() {
+        let sessions = SessionSlots::new(2);
+        let first = sessions.acquire().unwrap();
+        let _second = sessions.acquire().unwrap();
+        assert!(sessions.acquire().is_none());
+        drop(first);
+        assert!(sessions.acquire().is_some());
+    }
+
+    #[test]
+    fn test_helo_charges_connection_once() {
+        let config = SmtpConfig {
+            rate_limits: RateLimits::new(Some("1/60".parse().unwrap()), None, None),
+            greylist: None,
+            dnsbl: None,
+            spool: SpoolConfig {
+                max_size: 1024,
+                threshold: 1024,
+                dir: std::env::temp_dir(),
+            },
+            smtp_enabled: false,
+            listen: vec![],
+            tls_listen: vec![],
+            tls: None,
+            lmtp: None,
+            proxy_protocol: None,
+            max_sessions: 1,
+            lmtp_socket_mode: None,
+        };
+        let handler = MailHandler::new(
+            Client::new("id".to_string(), "secret".to_string()),
+            Store::in_memory().unwrap(),
+            MailUrlGen::new(
+                "http://web.test".to_string(),
+                vec![LinkKey::new("k1", b"0123456789abcdef").unwrap()],
+                Duration::from_secs(60),
+            )
+            .unwrap(),
+            &config,
+            Shutdown::default(),
+        );
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let mut session = handler.clone();
+        assert_eq!(session.helo(ip, "mx.test").code, 250);
+        // the EHLO after STARTTLS belongs to the same connection
+        assert_eq!(session.helo(ip, "mx.test").code, 250);
+        let mut next = handler;
+        assert_eq!(next.helo(ip, "mx.test").code, 421);
+        assert_eq!(next.helo(ip, "mx.test").code, 421);
+    }
+
+    #[test]
+    fn test_split_size_param() {
+        assert_eq!(
+            split_size_param("mail from:<a@b.com> SIZE=100 BODY=8BITMIME\r\n"),
+            Some((
+                "mail from:<a@b.com> BODY=8BITMIME\r\n".to_string(),
+                Some(100)
+            ))
+        );
+        assert_eq!(
+            split_size_param("MAIL FROM:<a@b.com> size=x\r\n"),
+            Some(("MAIL FROM:<a@b.com>\r\n".to_string(), None))
+        );
+        assert_eq!(split_size_param("MAIL FROM:<a@b.com>\r\n"), None);
+        assert_eq!(split_size_param("RCPT TO:<a@b.com> SIZE=1\r\n"), None);
+        assert!(check_size_param(Some(100), 100).is_none());
+        assert_eq!(check_size_param(Some(101), 100).unwrap().code, 552);
+        assert_eq!(check_size_param(None, 100).unwrap().code, 501);
+    }
+
+    #[test]
+    fn test_read_line() {
+        let mut reader = "HELO a\r\nMAIL FROM:<a@b.com>\r\n".as_bytes();
+        let mut line = vec![];
+        assert!(read_line(&mut reader, &mut line, 10).unwrap());
+        assert_eq!(line, b"HELO a\r\n");
+        assert!(!line_too_long(&line, 10));
+        assert!(read_line(&mut reader, &mut line, 10).unwrap());
+        assert_eq!(line, b"MAIL FROM:");
+        assert!(line_too_long(&line, 10));
+        let mut reader = "QUIT".as_bytes();
+        assert!(read_line(&mut reader, &mut line, 10).unwrap());
+        assert!(!line_too_long(&line, 10));
+        assert!(!read_line(&mut reader, &mut line, 10).unwrap());
+    }
+
+    #[test]
+    fn test_ehlo_with_size() {
+        let response = Response::custom(250, "mx.test".to_string());
+        assert_eq!(
+            ehlo_with_size(&response, 1024).unwrap(),
+            b"250-mx.test\r\n250 SIZE 1024\r\n"
+        );
+    }
+}
@@ -0,0 +1,74 @@
+use crate::store::Store;
+use anyhow::{anyhow, Result};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use log::info;
+use uuid::Uuid;
+
+pub struct Credential {
+    pub username: String,
+    pub password: String,
+}
+
+/// Issues a new credential for the chat, replacing the previous one. Only the
+/// argon2 hash is kept, the password is shown once when issued.
+pub fn issue_credential(store: &Store, chat_id: &str) -> Result<Credential> {
+    let username = chat_id.to_string();
+    let password = Uuid::new_v4().simple().to_string();
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("hash password error: {}", e))?
+        .to_string();
+    store.set_smtp_credential(&username, chat_id, &hash)?;
+    info!("issue smtp credential for chat: {}", chat_id);
+    Ok(Credential { username, password })
+}
+
+/// Returns the chat the credential belongs to if the password matches.
+pub fn authenticate(store: &Store, username: &str, password: &str) -> Result<Option<String>> {
+    let Some((chat_id, hash)) = store.get_smtp_credential(username)? else {
+        return Ok(None);
+    };
+    let hash = PasswordHash::new(&hash).map_err(|e| anyhow!("invalid password hash: {}", e))?;
+    if Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok()
+    {
+        Ok(Some(chat_id))
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_issue_and_authenticate() {
+        let store = Store::in_memory().unwrap();
+        let credential = issue_credential(&store, "chat").unwrap();
+        assert_eq!(credential.username, "chat");
+        assert_eq!(
+            authenticate(&store, "chat", &credential.password).unwrap(),
+            Some("chat".to_string())
+        );
+        assert_eq!(authenticate(&store, "chat", "wrong").unwrap(), None);
+        assert_eq!(
+            authenticate(&store, "other", &credential.password).unwrap(),
+            None
+        );
+
+        // issuing again revokes the old password
+        let renewed = issue_credential(&store, "chat").unwrap();
+        assert_eq!(
+            authenticate(&store, "chat", &credential.password).unwrap(),
+            None
+        );
+        assert!(authenticate(&store, "chat", &renewed.password)
+            .unwrap()
+            .is_some());
+    }
+}
@@ -0,0 +1,215 @@
+use crate::config;
+use anyhow::{anyhow, Context, Result};
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::Resolver;
+use log::{debug, error, info};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsblZone {
+    pub zone: String,
+    pub score: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Listing {
+    pub score: u32,
+    pub zones: Vec<String>,
+}
+
+/// Looks up client IPs in DNS blocklists. Each zone that lists the IP adds its
+/// score, and clients reaching `threshold` are rejected.
+#[derive(Clone)]
+pub struct Dnsbl {
+    zones: Vec<DnsblZone>,
+    threshold: u32,
+    resolver: Arc<Resolver>,
+    cache: Arc<Mutex<HashMap<IpAddr, (Instant, Listing)>>>,
+    cache_ttl: Duration,
+}
+
+impl Dnsbl {
+    pub fn new(
+        zones: Vec<DnsblZone>,
+        threshold: u32,
+        resolver: Option<SocketAddr>,
+        cache_ttl: Duration,
+    ) -> Result<Self> {
+        let resolver = match resolver {
+            Some(addr) => {
+                let servers =
+                    NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true);
+                let config = ResolverConfig::from_parts(None, vec![], servers);
+                let mut opts = ResolverOpts::default();
+                opts.timeout = Duration::from_secs(2);
+                Resolver::new(config, opts)?
+            }
+            None => Resolver::from_system_conf()?,
+        };
+        Ok(Dnsbl {
+            zones,
+            threshold,
+            resolver: Arc::new(resolver),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            cache_ttl,
+        })
+    }
+
+    /// DNSBL checks are enabled by setting `DNSBL_ZONES`.
+    pub fn from_env() -> Result<Option<Self>> {
+        let zones = match config::var("DNSBL_ZONES") {
+            Ok(z) if !z.trim().is_empty() => parse_zones(&z).context("DNSBL_ZONES")?,
+            _ => return Ok(None),
+        };
+        let threshold = match config::var("DNSBL_THRESHOLD") {
+            Ok(t) => t.parse().context("DNSBL_THRESHOLD")?,
+            Err(_) => 1,
+        };
+        let resolver = match config::var("DNSBL_RESOLVER") {
+            Ok(r) => Some(r.parse().context("DNSBL_RESOLVER")?),
+            Err(_) => None,
+        };
+        let cache_ttl = match config::var("DNSBL_CACHE_TTL") {
+            Ok(t) => t.parse().context("DNSBL_CACHE_TTL")?,
+            Err(_) => 3600,
+        };
+        info!("dnsbl zones: {:?}, threshold: {}", &zones, threshold);
+        Ok(Some(Dnsbl::new(
+            zones,
+            threshold,
+            resolver,
+            Duration::from_secs(cache_ttl),
+        )?))
+    }
+
+    /// Returns the listing if the client scored at or above the threshold.
+    pub fn blocked(&self, ip: IpAddr) -> Option<Listing> {
+        let listing = self.lookup(ip);
+        if listing.score >= self.threshold {
+            Some(listing)
+        } else {
+            None
+        }
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Listing {
+        if let Some((at, listing)) = self.cache.lock().unwrap().get(&ip) {
+            if at.elapsed() < self.cache_ttl {
+                return listing.clone();
+            }
+        }
+
+        let mut listing = Listing::default();
+        let mut complete = true;
+        for zone in &self.zones {
+            let name = query_name(ip, &zone.zone);
+            match self.resolver.ipv4_lookup(name.as_str()) {
+                Ok(answer) => {
+                    debug!(
+                        "dnsbl {} listed: {:?}",
+                        &name,
+                        answer.iter().collect::<Vec<_>>()
+                    );
+                    listing.score += zone.score;
+                    listing.zones.push(zone.zone.clone());
+                }
+                Err(e) => match e.kind() {
+                    ResolveErrorKind::NoRecordsFound { .. } => {}
+                    _ => {
+                        error!("dnsbl query {} error: {}", &name, e);
+                        complete = false;
+                    }
+                },
+            }
+        }
+
+        // failed lookups are retried on the next connection instead of being cached
+        if complete {
+            let mut cache = self.cache.lock().unwrap();
+            cache.retain(|_, (at, _)| at.elapsed() < self.cache_ttl);
+            cache.insert(ip, (Instant::now(), listing.clone()));
+        }
+        listing
+    }
+}
+
+/// Parses `zone[:score],...`, the score defaults to 1.
+pub fn parse_zones(s: &str) -> Result<Vec<DnsblZone>> {
+    s.split(',')
+        .map(|z| z.trim())
+        .filter(|z| !z.is_empty())
+        .map(|z| {
+            let (zone, score) = match z.split_once(':') {
+                Some((zone, score)) => (zone, score.parse()?),
+                None => (z, 1),
+            };
+            if zone.is_empty() {
+                return Err(anyhow!("empty dnsbl zone in `{}`", s));
+            }
+            Ok(DnsblZone {
+                zone: zone.trim_end_matches('.').to_string(),
+                score,
+            })
+        })
+        .collect()
+}
+
+/// Builds the fully qualified name to look up, e.g. `4.3.2.1.zen.spamhaus.org.` for `1.2.3.4`.
+fn query_name(ip: IpAddr, zone: &str) -> String {
+    let reversed = match ip {
+        IpAddr::V4(v4) => {
+            let o = v4.octets();
+            format!("{}.{}.{}.{}", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(v6) => v6
+            .octets()
+            .iter()
+            .rev()
+            .map(|b| format!("{:x}.{:x}", b & 0xf, b >> 4))
+            .collect::<Vec<_>>()
+            .join("."),
+    };
+    format!("{}.{}.", reversed, zone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_name() {
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        assert_eq!(
+            query_name(ip, "zen.spamhaus.org"),
+            "4.3.2.1.zen.spamhaus.org."
+        );
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(
+            query_name(ip, "bl.example"),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.bl.example."
+        );
+    }
+
+    #[test]
+    fn test_parse_zones() {
+        let zones = parse_zones("zen.spamhaus.org:10, bl.spamcop.net").unwrap();
+        assert_eq!(
+            zones,
+            vec![
+                DnsblZone {
+                    zone: "zen.spamhaus.org".to_string(),
+                    score: 10
+                },
+                DnsblZone {
+                    zone: "bl.spamcop.net".to_string(),
+                    score: 1
+                },
+            ]
+        );
+        assert!(parse_zones("zen.spamhaus.org:x").is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// What the SMTP session knows about a mail beyond its content.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MailEnvelope {
+    pub mail_from: String,
+    pub rcpts: Vec<String>,
+    pub helo: String,
+    pub client_ip: Option<IpAddr>,
+    pub tls: bool,
+    pub auth: Option<String>,
+    pub received_at: i64,
+}
+
+impl MailEnvelope {
+    /// Clears the per-transaction fields, connection fields survive RSET and further mails.
+    pub fn reset_transaction(&mut self) {
+        self.mail_from.clear();
+        self.rcpts.clear();
+        self.received_at = 0;
+    }
+
+    /// RFC 5321 section 4.4 trace header, prepended to the stored raw mail.
+    pub fn received_header(&self, by: &str, id: &str) -> String {
+        let from = match self.client_ip {
+            Some(ip) => format!("{} ([{}])", self.helo, ip),
+            None => self.helo.clone(),
+        };
+        let protocol = match (self.tls, self.auth.is_some()) {
+            (false, false) => "ESMTP",
+            (true, false) => "ESMTPS",
+            (false, true) => "ESMTPA",
+            (true, true) => "ESMTPSA",
+        };
+        let mut header = format!(
+            "Received: from {}\r\n\tby {} (Mailhook) with {} id {}",
+            from, by, protocol, id
+        );
+        if let [rcpt] = self.rcpts.as_slice() {
+            header.push_str(&format!("\r\n\tfor <{}>", rcpt));
+        }
+        let date = chrono::DateTime::from_timestamp(self.received_at, 0)
+            .unwrap_or_default()
+            .to_rfc2822();
+        header.push_str(&format!("; {}\r\n", date));
+        header
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::expect;
+
+    #[test]
+    fn test_received_header() {
+        let mut envelope = MailEnvelope {
+            mail_from: "a@b.com".to_string(),
+            rcpts: vec!["chat@mail.xcf.io".to_string()],
+            helo: "mx.b.com".to_string(),
+            client_ip: Some("1.2.3.4".parse().unwrap()),
+            tls: false,
+            auth: None,
+            received_at: 1615567842,
+        };
+        expect![[r#"
+            Received: from mx.b.com ([1.2.3.4])
+            	by mail.xcf.io (Mailhook) with ESMTP id mail_id
+            	for <chat@mail.xcf.io>; Fri, 12 Mar 2021 16:50:42 +0000
+        "#]]
+        .assert_eq(
+            &envelope
+                .received_header("mail.xcf.io", "mail_id")
+                .replace("\r\n", "\n"),
+        );
+
+        envelope.auth = Some("chat".to_string());
+        envelope.rcpts.push("other@mail.xcf.io".to_string());
+        expect![[r#"
+            Received: from mx.b.com ([1.2.3.4])
+            	by mail.xcf.io (Mailhook) with ESMTPA id mail_id; Fri, 12 Mar 2021 16:50:42 +0000
+        "#]]
+        .assert_eq(
+            &envelope
+                .received_header("mail.xcf.io", "mail_id")
+                .replace("\r\n", "\n"),
+        );
+    }
+}
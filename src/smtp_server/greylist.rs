@@ -0,0 +1,208 @@
+use crate::config;
+use crate::store::Store;
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Temporarily rejects unseen (client network, sender, recipient) triplets.
+/// Legitimate MTAs retry after a 4xx, most spam bots never do.
+#[derive(Clone)]
+pub struct Greylist {
+    delay: Duration,
+    ttl: Duration,
+    allowed_senders: Vec<String>,
+    allowed_networks: Vec<IpNet>,
+}
+
+impl Greylist {
+    pub fn new(
+        delay: Duration,
+        ttl: Duration,
+        allowed_senders: Vec<String>,
+        allowed_networks: Vec<IpNet>,
+    ) -> Self {
+        Greylist {
+            delay,
+            ttl,
+            allowed_senders: allowed_senders
+                .into_iter()
+                .map(|s| s.to_lowercase())
+                .collect(),
+            allowed_networks,
+        }
+    }
+
+    /// Greylisting is enabled by setting `GREYLIST_DELAY` (seconds).
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(delay) = config::var("GREYLIST_DELAY") else {
+            return Ok(None);
+        };
+        let delay = delay.parse().context("GREYLIST_DELAY")?;
+        let ttl = match config::var("GREYLIST_TTL") {
+            Ok(v) => v.parse().context("GREYLIST_TTL")?,
+            Err(_) => 36 * 24 * 3600,
+        };
+        let allowed_senders = list_env("GREYLIST_ALLOW_SENDERS");
+        let allowed_networks = list_env("GREYLIST_ALLOW_NETWORKS")
+            .iter()
+            .map(|n| parse_network(n))
+            .collect::<Result<_>>()
+            .context("GREYLIST_ALLOW_NETWORKS")?;
+        Ok(Some(Greylist::new(
+            Duration::from_secs(delay),
+            Duration::from_secs(ttl),
+            allowed_senders,
+            allowed_networks,
+        )))
+    }
+
+    /// Returns true if the triplet may pass now.
+    pub fn check(&self, store: &Store, ip: IpAddr, sender: &str, rcpt: &str) -> Result<bool> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        self.check_at(store, now, ip, sender, rcpt)
+    }
+
+    /// Deletes expired triplets, returns how many. Runs on the maintenance timer,
+    /// not in the SMTP session.
+    pub fn purge(&self, store: &Store) -> Result<usize> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        store.purge_greylist(now, self.ttl.as_secs() as i64)
+    }
+
+    fn check_at(
+        &self,
+        store: &Store,
+        now: i64,
+        ip: IpAddr,
+        sender: &str,
+        rcpt: &str,
+    ) -> Result<bool> {
+        if self.is_allowed(ip, sender) {
+            return Ok(true);
+        }
+        let triplet = format!(
+            "{} {} {}",
+            client_network(ip),
+            sender.to_lowercase(),
+            rcpt.to_lowercase()
+        );
+        let delay = self.delay.as_secs() as i64;
+        let ttl = self.ttl.as_secs() as i64;
+        match store.get_greylist(&triplet)? {
+            Some((_, Some(passed_until))) if passed_until >= now => {
+                store.pass_greylist(&triplet, now + ttl)?;
+                Ok(true)
+            }
+            Some((first_seen, None)) if now - first_seen < ttl => {
+                if now - first_seen >= delay {
+                    store.pass_greylist(&triplet, now + ttl)?;
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            }
+            _ => {
+                store.add_greylist(&triplet, now)?;
+                Ok(false)
+            }
+        }
+    }
+
+    fn is_allowed(&self, ip: IpAddr, sender: &str) -> bool {
+        if self.allowed_networks.iter().any(|n| n.contains(&ip)) {
+            return true;
+        }
+        let sender = sender.to_lowercase();
+        self.allowed_senders.iter().any(|allowed| {
+            if allowed.starts_with('@') {
+                sender.ends_with(allowed.as_str())
+            } else if allowed.contains('@') {
+                &sender == allowed
+            } else {
+                sender.ends_with(&format!("@{}", allowed))
+            }
+        })
+    }
+}
+
+fn list_env(name: &str) -> Vec<String> {
+    config::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Accepts both CIDR ranges and single addresses.
+pub fn parse_network(s: &str) -> Result<IpNet> {
+    if let Ok(net) = s.parse() {
+        return Ok(net);
+    }
+    let ip: IpAddr = s.parse()?;
+    Ok(IpNet::from(ip))
+}
+
+/// Senders often retry from a different host of the same pool, so only the /24 (or /64) counts.
+fn client_network(ip: IpAddr) -> IpNet {
+    let prefix = match ip {
+        IpAddr::V4(_) => 24,
+        IpAddr::V6(_) => 64,
+    };
+    IpNet::new(ip, prefix).unwrap().trunc()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn greylist() -> Greylist {
+        Greylist::new(
+            Duration::from_secs(300),
+            Duration::from_secs(3600),
+            vec!["@trusted.com".to_string(), "boss@example.com".to_string()],
+            vec![parse_network("10.0.0.0/8").unwrap()],
+        )
+    }
+
+    #[test]
+    fn test_greylist_triplet() {
+        let store = Store::in_memory().unwrap();
+        let greylist = greylist();
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let other_host: IpAddr = "1.2.3.5".parse().unwrap();
+        let (from, to) = ("a@b.com", "chat@test");
+        assert!(!greylist.check_at(&store, 1000, ip, from, to).unwrap());
+        assert!(!greylist.check_at(&store, 1100, ip, from, to).unwrap());
+        assert!(greylist
+            .check_at(&store, 1300, other_host, from, to)
+            .unwrap());
+        assert!(greylist.check_at(&store, 2000, ip, from, to).unwrap());
+        assert!(!greylist.check_at(&store, 2000, ip, "c@b.com", to).unwrap());
+        // passed triplets are forgotten after ttl without traffic
+        assert!(!greylist.check_at(&store, 10000, ip, from, to).unwrap());
+        // only the stale `c@b.com` triplet is left for the timer to purge
+        assert_eq!(store.purge_greylist(10000, 3600).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_greylist_allowlist() {
+        let store = Store::in_memory().unwrap();
+        let greylist = greylist();
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        assert!(greylist
+            .check_at(&store, 0, ip, "x@trusted.com", "chat")
+            .unwrap());
+        assert!(greylist
+            .check_at(&store, 0, ip, "Boss@example.com", "chat")
+            .unwrap());
+        assert!(!greylist
+            .check_at(&store, 0, ip, "other@example.com", "chat")
+            .unwrap());
+        let internal: IpAddr = "10.1.2.3".parse().unwrap();
+        assert!(greylist
+            .check_at(&store, 0, internal, "a@b.com", "chat")
+            .unwrap());
+    }
+}
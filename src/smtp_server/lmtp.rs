@@ -0,0 +1,578 @@
+use crate::smtp_server::{
+    check_size_param, line_too_long, read_line, split_size_param, MailHandler, UnparsableMail,
+    MAX_COMMAND_LINE,
+};
+use crate::{listen, metrics, telemetry};
+use anyhow::{anyhow, bail, ensure, Result};
+use log::{error, info, warn};
+use mailin_embedded::{Handler, Response};
+use opentelemetry::KeyValue;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpListener};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+
+const NAME: &str = "Mailhook LMTP Server";
+
+/// `host:port` for TCP or `unix:/path/to/socket`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LmtpAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for LmtpAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(LmtpAddr::Unix(PathBuf::from(path))),
+            None => Ok(LmtpAddr::Tcp(s.to_string())),
+        }
+    }
+}
+
+pub enum LmtpListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl LmtpListener {
+    /// `mode` sets the permissions of a Unix socket, the umask applies when None.
+    pub fn bind(addr: &LmtpAddr, mode: Option<u32>) -> Result<Self> {
+        info!("LMTP Server: {:?}", addr);
+        match addr {
+            LmtpAddr::Tcp(addr) => Ok(LmtpListener::Tcp(TcpListener::bind(addr)?)),
+            LmtpAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                let listener = UnixListener::bind(path)?;
+                if let Some(mode) = mode {
+                    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+                }
+                Ok(LmtpListener::Unix(listener))
+            }
+        }
+    }
+}
+
+/// A socket left by a previous run would make bind fail, anything else at the
+/// path, or a socket still in use, is refused rather than deleted.
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if !metadata.file_type().is_socket() {
+        bail!("{} exists and is not a socket", path.display());
+    }
+    if UnixStream::connect(path).is_ok() {
+        bail!("{} is in use by another process", path.display());
+    }
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Parses an octal mode like `0660`.
+pub fn parse_socket_mode(s: &str) -> Result<u32> {
+    let mode = u32::from_str_radix(s.trim_start_matches("0o"), 8)
+        .map_err(|_| anyhow!("invalid socket mode `{}`, expected octal like 0660", s))?;
+    ensure!(mode <= 0o777, "invalid socket mode `{}`", s);
+    Ok(mode)
+}
+
+/// Unix socket peers have no address and are recorded as 127.0.0.1.
+pub(super) fn serve(mut handler: MailHandler, listener: LmtpListener) -> Result<()> {
+    handler.ip_checks = false;
+    let shutdown = handler.shutdown.clone();
+    match listener {
+        LmtpListener::Tcp(listener) => {
+            listener.set_nonblocking(true)?;
+            listen::accept_loop(
+                "lmtp",
+                &shutdown,
+                || listener.accept(),
+                |(stream, addr)| {
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        error!("lmtp socket error: {}", e);
+                        return;
+                    }
+                    match stream.try_clone() {
+                        Ok(reader) => spawn_session(handler.clone(), addr.ip(), reader, stream),
+                        Err(e) => error!("lmtp socket error: {}", e),
+                    }
+                },
+            );
+        }
+        LmtpListener::Unix(listener) => {
+            listener.set_nonblocking(true)?;
+            listen::accept_loop(
+                "lmtp",
+                &shutdown,
+                || listener.accept(),
+                |(stream, _)| {
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        error!("lmtp socket error: {}", e);
+                        return;
+                    }
+                    match stream.try_clone() {
+                        Ok(reader) => spawn_session(
+                            handler.clone(),
+                            IpAddr::V4(Ipv4Addr::LOCALHOST),
+                            reader,
+                            stream,
+                        ),
+                        Err(e) => error!("lmtp socket error: {}", e),
+                    }
+                },
+            );
+        }
+    }
+    Ok(())
+}
+
+fn spawn_session<R, W>(handler: MailHandler, ip: IpAddr, reader: R, writer: W)
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    thread::spawn(move || {
+        let _span = telemetry::span(
+            "lmtp.session",
+            vec![KeyValue::new("net.peer.ip", ip.to_string())],
+        );
+        if let Err(e) = session(handler, ip, BufReader::new(reader), writer) {
+            error!("lmtp session error: {}", e);
+        }
+    });
+}
+
+fn reply(writer: &mut impl Write, code: u16, message: &str) -> io::Result<()> {
+    writer.write_all(format!("{} {}\r\n", code, message).as_bytes())
+}
+
+fn respond(writer: &mut impl Write, response: &Response) -> io::Result<()> {
+    response.write_to(writer)?;
+    Ok(())
+}
+
+/// Runs one LMTP (RFC 2033) session, delegating every step to the SMTP handler.
+/// After DATA every accepted recipient gets its own reply.
+fn session(
+    mut handler: MailHandler,
+    ip: IpAddr,
+    mut reader: impl BufRead,
+    mut writer: impl Write,
+) -> io::Result<()> {
+    reply(&mut writer, 220, &format!("{} ready", NAME))?;
+    let mut helo: Option<String> = None;
+    let mut from: Option<String> = None;
+    let mut rcpts: Vec<String> = vec![];
+    let mut line = Vec::new();
+    loop {
+        if !read_line(&mut reader, &mut line, MAX_COMMAND_LINE)? {
+            return Ok(());
+        }
+        if line_too_long(&line, MAX_COMMAND_LINE) {
+            reply(&mut writer, 500, "5.5.2 Line too long")?;
+            return Ok(());
+        }
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end_matches(['\r', '\n']);
+        let (verb, arg) = match text.split_once(' ') {
+            Some((verb, arg)) => (verb, arg.trim()),
+            None => (text, ""),
+        };
+        match verb.to_ascii_uppercase().as_str() {
+            "LHLO" => {
+                if arg.is_empty() {
+                    reply(&mut writer, 501, "5.5.4 Syntax: LHLO hostname")?;
+                    continue;
+                }
+                let response = handler.helo(ip, arg);
+                if response.code >= 400 {
+                    respond(&mut writer, &response)?;
+                    if response.code == 421 {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                handler.clear();
+                from = None;
+                rcpts.clear();
+                helo = Some(arg.to_string());
+                writer.write_all(
+                    format!(
+                        "250-{}\r\n250-PIPELINING\r\n250-SIZE {}\r\n250 8BITMIME\r\n",
+                        NAME,
+                        handler.body.max_size()
+                    )
+                    .as_bytes(),
+                )?;
+            }
+            "MAIL" => {
+                let Some(domain) = &helo else {
+                    reply(&mut writer, 503, "5.5.1 Send LHLO first")?;
+                    continue;
+                };
+                if from.is_some() {
+                    reply(&mut writer, 503, "5.5.1 Nested MAIL command")?;
+                    continue;
+                }
+                let Some(addr) = path_arg(arg, "FROM:") else {
+                    reply(&mut writer, 501, "5.5.4 Syntax: MAIL FROM:<address>")?;
+                    continue;
+                };
+                if let Some((_, size)) = split_size_param(text) {
+                    if let Some(response) = check_size_param(size, handler.body.max_size()) {
+                        respond(&mut writer, &response)?;
+                        continue;
+                    }
+                }
+                let response = handler.mail(ip, domain, &addr);
+                respond(&mut writer, &response)?;
+                if response.code < 400 {
+                    from = Some(addr);
+                }
+            }
+            "RCPT" => {
+                if from.is_none() {
+                    reply(&mut writer, 503, "5.5.1 Send MAIL first")?;
+                    continue;
+                }
+                let Some(addr) = path_arg(arg, "TO:") else {
+                    reply(&mut writer, 501, "5.5.4 Syntax: RCPT TO:<address>")?;
+                    continue;
+                };
+                match handler.store.rcpt_chat(&addr) {
+                    Ok(Some(_)) => {}
+                    Ok(None) => {
+                        metrics::reject("unknown_chat");
+                        reply(&mut writer, 550, &format!("5.1.1 <{}> unknown chat", addr))?;
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("resolve recipient {} error: {}", addr, e);
+                        reply(&mut writer, 451, "4.3.0 Local error, try again later")?;
+                        continue;
+                    }
+                }
+                let response = handler.rcpt(&addr);
+                respond(&mut writer, &response)?;
+                if response.code < 400 {
+                    rcpts.push(addr);
+                }
+            }
+            "DATA" => {
+                let (Some(domain), Some(sender)) = (&helo, &from) else {
+                    reply(&mut writer, 503, "5.5.1 Send MAIL first")?;
+                    continue;
+                };
+                if rcpts.is_empty() {
+                    reply(&mut writer, 503, "5.5.1 No valid recipients")?;
+                    continue;
+                }
+                let response = handler.data_start(domain, sender, false, &rcpts);
+                if response.code >= 400 {
+                    respond(&mut writer, &response)?;
+                    continue;
+                }
+                reply(&mut writer, 354, "Start mail input; end with <CRLF>.<CRLF>")?;
+                let limit = handler.body.max_size() as u64 + 2;
+                loop {
+                    if !read_line(&mut reader, &mut line, limit)? {
+                        return Ok(());
+                    }
+                    if line_too_long(&line, limit) {
+                        metrics::reject("too_large");
+                        reply(&mut writer, 552, "5.3.4 Message size exceeds maximum")?;
+                        return Ok(());
+                    }
+                    if line == b".\r\n" || line == b".\n" {
+                        break;
+                    }
+                    let data = line.strip_prefix(b".").unwrap_or(&line);
+                    handler.data(data)?;
+                }
+                if handler.body.exceeded() {
+                    metrics::reject("too_large");
+                    handler.clear();
+                    for rcpt in &rcpts {
+                        reply(
+                            &mut writer,
+                            552,
+                            &format!("5.3.4 <{}> message size exceeds maximum", rcpt),
+                        )?;
+                    }
+                } else {
+                    metrics::MESSAGES_ACCEPTED.inc();
+                    metrics::MESSAGE_SIZE.observe(handler.body.len() as f64);
+                    let delivered = handler.deliver();
+                    let stored = delivered.is_ok();
+                    let results = delivered.unwrap_or_else(|e| {
+                        // every recipient gets a temporary failure, the MTA keeps the mail
+                        let e = format!("mail not stored: {}", e);
+                        rcpts
+                            .iter()
+                            .map(|rcpt| (rcpt.clone(), Err(anyhow!("{}", e))))
+                            .collect()
+                    });
+                    for (rcpt, ret) in &results {
+                        let (code, text) = data_reply(rcpt, ret, stored);
+                        reply(&mut writer, code, &text)?;
+                    }
+                }
+                from = None;
+                rcpts.clear();
+            }
+            "RSET" => {
+                handler.clear();
+                from = None;
+                rcpts.clear();
+                reply(&mut writer, 250, "2.0.0 OK")?;
+            }
+            "NOOP" => reply(&mut writer, 250, "2.0.0 OK")?,
+            "QUIT" => {
+                reply(&mut writer, 221, "2.0.0 Bye")?;
+                return Ok(());
+            }
+            "HELO" | "EHLO" => reply(&mut writer, 500, "5.5.1 This is LMTP, use LHLO")?,
+            _ => reply(&mut writer, 500, "5.5.2 Command not recognized")?,
+        }
+    }
+}
+
+/// The DATA reply for one recipient. Only a mail that wasn't stored is worth a retry,
+/// a stored one is readable on the web even when forwarding failed and a retry would
+/// only store another copy.
+fn data_reply(rcpt: &str, ret: &Result<()>, stored: bool) -> (u16, String) {
+    match ret {
+        Ok(()) => (250, format!("2.0.0 <{}> delivered", rcpt)),
+        Err(e) if !stored => {
+            error!("deliver to {} failed: {}", rcpt, e);
+            (451, format!("4.3.0 <{}> {}", rcpt, e))
+        }
+        Err(e) if e.is::<UnparsableMail>() => {
+            error!("deliver to {} failed: {}", rcpt, e);
+            (554, format!("5.6.0 <{}> {}", rcpt, e))
+        }
+        Err(e) => {
+            warn!("forward to {} failed, the mail is stored: {}", rcpt, e);
+            (250, format!("2.0.0 <{}> stored, not forwarded", rcpt))
+        }
+    }
+}
+
+/// Extracts the address from `FROM:<a@b.com> SIZE=100`, the null sender `<>` gives "".
+fn path_arg(arg: &str, prefix: &str) -> Option<String> {
+    if arg.len() < prefix.len() || !arg[..prefix.len()].eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let path = arg[prefix.len()..].trim_start();
+    match path.strip_prefix('<') {
+        Some(rest) => rest.split_once('>').map(|(addr, _)| addr.to_string()),
+        None => path.split_whitespace().next().map(|addr| addr.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot_server::feishu_client::{Client, DryRun};
+    use crate::bot_server::mail_url::LinkKey;
+    use crate::bot_server::MailUrlGen;
+    use crate::shutdown::Shutdown;
+    use crate::smtp_server::rate_limit::RateLimits;
+    use crate::smtp_server::spool::SpoolConfig;
+    use crate::smtp_server::SmtpConfig;
+    use crate::store::Store;
+    use expect_test::expect;
+    use std::time::Duration;
+
+    #[test]
+    fn test_path_arg() {
+        assert_eq!(
+            path_arg("FROM:<a@b.com> SIZE=100", "FROM:"),
+            Some("a@b.com".to_string())
+        );
+        assert_eq!(path_arg("from: <>", "FROM:"), Some("".to_string()));
+        assert_eq!(path_arg("TO:c@d.com", "TO:"), Some("c@d.com".to_string()));
+        assert_eq!(path_arg("TO:<c@d.com", "TO:"), None);
+        assert_eq!(path_arg("<c@d.com>", "TO:"), None);
+    }
+
+    #[test]
+    fn test_data_reply() {
+        let reply = |ret: Result<()>, stored| data_reply("a@test", &ret, stored);
+        assert_eq!(reply(Ok(()), true).0, 250);
+        assert_eq!(reply(Err(anyhow!("disk full")), false).0, 451);
+        assert_eq!(
+            reply(Err(anyhow!(UnparsableMail)), true),
+            (554, "5.6.0 <a@test> mail could not be parsed".to_string())
+        );
+        assert_eq!(
+            reply(Err(anyhow!("feishu down")), true),
+            (250, "2.0.0 <a@test> stored, not forwarded".to_string())
+        );
+    }
+
+    #[test]
+    fn test_bind_unix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lmtp.sock");
+        let addr = LmtpAddr::Unix(path.clone());
+
+        let listener = LmtpListener::bind(&addr, Some(0o660)).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        assert!(LmtpListener::bind(&addr, None).is_err());
+        drop(listener);
+        // the socket file outlives the listener
+        assert!(LmtpListener::bind(&addr, None).is_ok());
+
+        let file = dir.path().join("data");
+        fs::write(&file, "keep").unwrap();
+        assert!(LmtpListener::bind(&LmtpAddr::Unix(file.clone()), None).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep");
+
+        assert_eq!(parse_socket_mode("0660").unwrap(), 0o660);
+        assert_eq!(parse_socket_mode("600").unwrap(), 0o600);
+        assert!(parse_socket_mode("0980").is_err());
+        assert!(parse_socket_mode("01777").is_err());
+    }
+
+    #[test]
+    fn test_session() {
+        let config = SmtpConfig {
+            rate_limits: RateLimits::new(None, None, None),
+            greylist: None,
+            dnsbl: None,
+            spool: SpoolConfig {
+                max_size: 1024,
+                threshold: 1024,
+                dir: std::env::temp_dir(),
+            },
+            smtp_enabled: false,
+            listen: vec![],
+            tls_listen: vec![],
+            tls: None,
+            lmtp: None,
+            proxy_protocol: None,
+            max_sessions: 1,
+            lmtp_socket_mode: None,
+        };
+        let store = Store::in_memory().unwrap();
+        store.add_bot_to_chat("chat").unwrap();
+        let handler = MailHandler::new(
+            Client::new("id".to_string(), "secret".to_string()),
+            store,
+            MailUrlGen::new(
+                "http://web.test".to_string(),
+                vec![LinkKey::new("k1", b"0123456789abcdef").unwrap()],
+                Duration::from_secs(60),
+            )
+            .unwrap(),
+            &config,
+            Shutdown::default(),
+        );
+        let input = "EHLO mx.test\r\nMAIL FROM:<a@b.com>\r\nLHLO mx.test\r\nRCPT TO:<chat@test>\r\n\
+            MAIL FROM:<a@b.com> SIZE=2048\r\nMAIL FROM:<a@b.com> SIZE=512\r\nRCPT TO:<unknown@test>\r\nRCPT TO:<chat@test>\r\nRSET\r\nDATA\r\nQUIT\r\n";
+        let mut output = Vec::new();
+        session(
+            handler,
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+        expect![[r#"
+            220 Mailhook LMTP Server ready
+            500 5.5.1 This is LMTP, use LHLO
+            503 5.5.1 Send LHLO first
+            250-Mailhook LMTP Server
+            250-PIPELINING
+            250-SIZE 1024
+            250 8BITMIME
+            503 5.5.1 Send MAIL first
+            552 5.3.4 Message size exceeds fixed maximum message size
+            250 OK
+            550 5.1.1 <unknown@test> unknown chat
+            250 OK
+            250 2.0.0 OK
+            503 5.5.1 Send MAIL first
+            221 2.0.0 Bye
+        "#]]
+        .assert_eq(&String::from_utf8(output).unwrap().replace("\r\n", "\n"));
+    }
+
+    #[test]
+    fn test_data_replies_in_rcpt_order() {
+        let config = SmtpConfig {
+            rate_limits: RateLimits::new(None, None, None),
+            greylist: None,
+            dnsbl: None,
+            spool: SpoolConfig {
+                max_size: 1024,
+                threshold: 1024,
+                dir: std::env::temp_dir(),
+            },
+            smtp_enabled: false,
+            listen: vec![],
+            tls_listen: vec![],
+            tls: None,
+            lmtp: None,
+            proxy_protocol: None,
+            max_sessions: 1,
+            lmtp_socket_mode: None,
+        };
+        let store = Store::in_memory().unwrap();
+        store.add_bot_to_chat("chat").unwrap();
+        store.add_bot_to_chat("quiet").unwrap();
+        let mut settings = store.chat_settings("quiet").unwrap().unwrap();
+        settings.muted = true;
+        store.set_chat_settings("quiet", &settings).unwrap();
+        let handler = MailHandler::new(
+            Client::new("id".to_string(), "secret".to_string()).with_dry_run(Some(DryRun::Log)),
+            store,
+            MailUrlGen::new(
+                "http://web.test".to_string(),
+                vec![LinkKey::new("k1", b"0123456789abcdef").unwrap()],
+                Duration::from_secs(60),
+            )
+            .unwrap(),
+            &config,
+            Shutdown::default(),
+        );
+        // the muted chat is answered before the chat that is forwarded to
+        let input = "LHLO mx.test\r\nMAIL FROM:<a@b.com>\r\nRCPT TO:<chat@test>\r\n\
+            RCPT TO:<unknown@test>\r\nRCPT TO:<quiet@test>\r\nDATA\r\nSubject: hi\r\n\r\nbody\r\n.\r\nQUIT\r\n";
+        let mut output = Vec::new();
+        session(
+            handler,
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+        expect![[r#"
+            220 Mailhook LMTP Server ready
+            250-Mailhook LMTP Server
+            250-PIPELINING
+            250-SIZE 1024
+            250 8BITMIME
+            250 OK
+            250 OK
+            550 5.1.1 <unknown@test> unknown chat
+            250 OK
+            354 Start mail input; end with <CRLF>.<CRLF>
+            250 2.0.0 <chat@test> delivered
+            250 2.0.0 <quiet@test> delivered
+            221 2.0.0 Bye
+        "#]]
+        .assert_eq(&String::from_utf8(output).unwrap().replace("\r\n", "\n"));
+    }
+}
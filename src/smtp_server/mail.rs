@@ -1,6 +1,11 @@
+use crate::smtp_server::envelope::MailEnvelope;
 use anyhow::Result;
 use melib::attachments::DecodeOptions;
 use melib::Envelope;
+use std::io::{self, BufRead, BufReader, Read};
+
+/// Longest header block read from a stored or spooled mail.
+pub const MAX_HEADER_SIZE: u64 = 64 * 1024;
 
 pub struct MailContent {
     pub text: String,
@@ -26,11 +31,130 @@ pub fn get_data_from_mail(mail: &[u8]) -> Result<MailContent> {
     Ok(MailContent { text, files })
 }
 
+/// What the web view shows of a mail.
+pub struct ParsedMail {
+    pub subject: String,
+    pub from: String,
+    pub to: String,
+    pub cc: String,
+    pub date: String,
+    pub text: String,
+    pub html: Option<String>,
+    /// In the same order as `MailContent::files`.
+    pub attachments: Vec<MailAttachment>,
+}
+
+pub struct MailAttachment {
+    pub filename: String,
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Reads the header block of a mail, up to the first empty line.
+pub fn read_headers(mail: impl Read) -> io::Result<Vec<u8>> {
+    let mut reader = BufReader::new(mail.take(MAX_HEADER_SIZE));
+    let mut headers = vec![];
+    loop {
+        let start = headers.len();
+        if reader.read_until(b'\n', &mut headers)? == 0 {
+            break;
+        }
+        if headers[start..] == *b"\r\n" || headers[start..] == *b"\n" {
+            return Ok(headers);
+        }
+    }
+    headers.extend_from_slice(b"\r\n");
+    Ok(headers)
+}
+
+/// Only the headers are parsed, for indexing.
+pub fn parse_subject(mail: &[u8]) -> Result<String> {
+    let envelope = Envelope::from_bytes(mail, None)?;
+    Ok(envelope.subject().into_owned())
+}
+
+pub fn parse_mail(mail: &[u8]) -> Result<ParsedMail> {
+    let envelope = Envelope::from_bytes(mail, None)?;
+    let body = envelope.body_bytes(mail);
+    let mut html = None;
+    let mut attachments = Vec::new();
+    for part in body.attachments() {
+        match part.filename() {
+            Some(filename) => attachments.push(MailAttachment {
+                filename,
+                mime_type: part.mime_type(),
+                data: part.decode(DecodeOptions::default()),
+            }),
+            None if html.is_none() && part.is_html() => {
+                let bytes = part.decode(DecodeOptions::default());
+                html = Some(String::from_utf8_lossy(&bytes).into_owned());
+            }
+            None => {}
+        }
+    }
+    Ok(ParsedMail {
+        subject: envelope.subject().into_owned(),
+        from: envelope.field_from_to_string(),
+        to: envelope.field_to_to_string(),
+        cc: envelope.field_cc_to_string(),
+        date: envelope.date_as_str().to_string(),
+        text: body.text(),
+        html,
+        attachments,
+    })
+}
+
+pub fn format_text(content: &MailContent, envelope: &MailEnvelope, url: &str) -> String {
+    let mut text = content.text.clone();
+    text.push('\n');
+    if !envelope.mail_from.is_empty() {
+        text.push_str(&format!("\nfrom: {}", envelope.mail_from));
+    }
+    text.push_str(&format!("\nview mail: {}", url));
+    text
+}
+
+/// Lines for attachments that could not be uploaded to Feishu, as `(filename, url)`.
+pub fn format_attachment_links(links: &[(String, String)]) -> String {
+    links
+        .iter()
+        .map(|(filename, url)| format!("\nattachment {}: {}", filename, url))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
+    use super::{read_headers, MAX_HEADER_SIZE};
     use expect_test::expect;
     use melib::{Attachment, Envelope};
 
+    #[test]
+    fn test_read_headers() {
+        let mail = "Subject: hi\r\nFrom: a@b.com\r\n\r\nbody\r\n";
+        assert_eq!(
+            read_headers(mail.as_bytes()).unwrap(),
+            b"Subject: hi\r\nFrom: a@b.com\r\n\r\n"
+        );
+        assert_eq!(
+            read_headers("Subject: hi\n".as_bytes()).unwrap(),
+            b"Subject: hi\n\r\n"
+        );
+        let long = "X: y\r\n".repeat(20000);
+        assert_eq!(
+            read_headers(long.as_bytes()).unwrap().len() as u64,
+            MAX_HEADER_SIZE + 2
+        );
+    }
+
+    #[test]
+    fn test_format_attachment_links() {
+        let links = [("a.zip".to_string(), "https://web.test/a".to_string())];
+        assert_eq!(
+            super::format_attachment_links(&links),
+            "\nattachment a.zip: https://web.test/a"
+        );
+    }
+
     #[test]
     fn test_parse_mail() {
         let content = r#"Received: by mail-ua1-f41.google.com with SMTP id o8so1970899uar.3
@@ -0,0 +1,253 @@
+use crate::config;
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const MAX_TRACKED_KEYS: usize = 10_000;
+/// How many of the least recently seen keys are dropped when the map is still
+/// full after dropping refilled buckets, so the scan is not paid on every new key.
+const EVICT_KEYS: usize = MAX_TRACKED_KEYS / 10;
+
+/// `burst` tokens refilled evenly over `per`, written as `count/seconds`, e.g. `30/60`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub burst: u32,
+    pub per: Duration,
+}
+
+impl Rate {
+    fn tokens_per_sec(&self) -> f64 {
+        self.burst as f64 / self.per.as_secs_f64()
+    }
+}
+
+impl FromStr for Rate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (burst, secs) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow!("invalid rate `{}`, expected `count/seconds`", s))?;
+        let burst: u32 = burst.trim().parse()?;
+        let secs: u64 = secs.trim().trim_end_matches('s').parse()?;
+        if burst == 0 || secs == 0 {
+            return Err(anyhow!(
+                "invalid rate `{}`, count and seconds must be positive",
+                s
+            ));
+        }
+        Ok(Rate {
+            burst,
+            per: Duration::from_secs(secs),
+        })
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Clone)]
+pub struct RateLimiter<K> {
+    rate: Rate,
+    buckets: Arc<Mutex<HashMap<K, TokenBucket>>>,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(rate: Rate) -> Self {
+        RateLimiter {
+            rate,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Takes a token for `key`, returns false if its bucket is empty.
+    pub fn check(&self, key: K) -> bool {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: K, now: Instant) -> bool {
+        let burst = self.rate.burst as f64;
+        let refill = self.rate.tokens_per_sec();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_KEYS && !buckets.contains_key(&key) {
+            buckets.retain(|_, b| {
+                b.tokens + now.saturating_duration_since(b.updated).as_secs_f64() * refill < burst
+            });
+            if buckets.len() >= MAX_TRACKED_KEYS {
+                evict_least_recent(&mut buckets, EVICT_KEYS);
+            }
+        }
+        let bucket = buckets.entry(key).or_insert(TokenBucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Drops the `count` buckets that were updated longest ago.
+fn evict_least_recent<K: Eq + Hash>(buckets: &mut HashMap<K, TokenBucket>, count: usize) {
+    let mut updated: Vec<Instant> = buckets.values().map(|b| b.updated).collect();
+    let count = count.clamp(1, updated.len());
+    let (_, cutoff, _) = updated.select_nth_unstable(count - 1);
+    let cutoff = *cutoff;
+    let mut left = count;
+    buckets.retain(|_, b| {
+        if left > 0 && b.updated <= cutoff {
+            left -= 1;
+            false
+        } else {
+            true
+        }
+    });
+}
+
+pub enum ChatLimit {
+    Allowed,
+    /// `first` is true only for the first rejection since the chat was last allowed,
+    /// so the chat gets a single notice per limited period.
+    Limited {
+        first: bool,
+    },
+}
+
+#[derive(Clone)]
+pub struct RateLimits {
+    conn_per_ip: Option<RateLimiter<IpAddr>>,
+    mail_per_ip: Option<RateLimiter<IpAddr>>,
+    mail_per_chat: Option<RateLimiter<String>>,
+    limited_chats: Arc<Mutex<HashSet<String>>>,
+}
+
+impl RateLimits {
+    pub fn new(
+        conn_per_ip: Option<Rate>,
+        mail_per_ip: Option<Rate>,
+        mail_per_chat: Option<Rate>,
+    ) -> Self {
+        RateLimits {
+            conn_per_ip: conn_per_ip.map(RateLimiter::new),
+            mail_per_ip: mail_per_ip.map(RateLimiter::new),
+            mail_per_chat: mail_per_chat.map(RateLimiter::new),
+            limited_chats: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub fn from_env() -> Result<Self> {
+        fn rate(name: &str) -> Result<Option<Rate>> {
+            match config::var(name) {
+                Ok(v) if !v.is_empty() => Ok(Some(v.parse().context(name.to_string())?)),
+                _ => Ok(None),
+            }
+        }
+        Ok(RateLimits::new(
+            rate("SMTP_CONN_RATE_PER_IP")?,
+            rate("SMTP_MAIL_RATE_PER_IP")?,
+            rate("SMTP_MAIL_RATE_PER_CHAT")?,
+        ))
+    }
+
+    pub fn allow_connection(&self, ip: IpAddr) -> bool {
+        match &self.conn_per_ip {
+            Some(limiter) => limiter.check(ip),
+            None => true,
+        }
+    }
+
+    pub fn allow_mail_from(&self, ip: IpAddr) -> bool {
+        match &self.mail_per_ip {
+            Some(limiter) => limiter.check(ip),
+            None => true,
+        }
+    }
+
+    pub fn allow_mail_to_chat(&self, chat_id: &str) -> ChatLimit {
+        let Some(limiter) = &self.mail_per_chat else {
+            return ChatLimit::Allowed;
+        };
+        let mut limited = self.limited_chats.lock().unwrap();
+        if limiter.check(chat_id.to_string()) {
+            limited.remove(chat_id);
+            ChatLimit::Allowed
+        } else {
+            ChatLimit::Limited {
+                first: limited.insert(chat_id.to_string()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        let rate: Rate = "30/60".parse().unwrap();
+        assert_eq!(rate.burst, 30);
+        assert_eq!(rate.per, Duration::from_secs(60));
+        assert_eq!("5/10s".parse::<Rate>().unwrap().burst, 5);
+        assert!("0/10".parse::<Rate>().is_err());
+        assert!("10".parse::<Rate>().is_err());
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new("2/10".parse().unwrap());
+        let now = Instant::now();
+        assert!(limiter.check_at("a", now));
+        assert!(limiter.check_at("a", now));
+        assert!(!limiter.check_at("a", now));
+        assert!(limiter.check_at("b", now));
+        assert!(!limiter.check_at("a", now + Duration::from_secs(4)));
+        assert!(limiter.check_at("a", now + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_evicts_least_recent_when_full() {
+        let limiter = RateLimiter::new("1/3600".parse().unwrap());
+        let now = Instant::now();
+        for i in 0..MAX_TRACKED_KEYS {
+            assert!(limiter.check_at(i, now + Duration::from_millis(i as u64)));
+        }
+        let late = now + Duration::from_secs(1);
+        assert!(limiter.check_at(MAX_TRACKED_KEYS, late));
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MAX_TRACKED_KEYS - EVICT_KEYS + 1);
+        assert!(!buckets.contains_key(&0));
+        assert!(buckets.contains_key(&(MAX_TRACKED_KEYS - 1)));
+        drop(buckets);
+        assert!(!limiter.check_at(MAX_TRACKED_KEYS - 1, late));
+    }
+
+    #[test]
+    fn test_chat_limit_notifies_once() {
+        let limits = RateLimits::new(None, None, Some("1/3600".parse().unwrap()));
+        assert!(matches!(
+            limits.allow_mail_to_chat("chat"),
+            ChatLimit::Allowed
+        ));
+        assert!(matches!(
+            limits.allow_mail_to_chat("chat"),
+            ChatLimit::Limited { first: true }
+        ));
+        assert!(matches!(
+            limits.allow_mail_to_chat("chat"),
+            ChatLimit::Limited { first: false }
+        ));
+    }
+}
@@ -0,0 +1,189 @@
+use crate::config;
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub struct SpoolConfig {
+    /// Mails larger than this are rejected with 552.
+    pub max_size: usize,
+    /// Mails larger than this are written to a temp file instead of kept in memory.
+    pub threshold: usize,
+    pub dir: PathBuf,
+}
+
+impl SpoolConfig {
+    pub fn from_env() -> Result<Self> {
+        let max_size = match config::var("SMTP_MAX_MESSAGE_SIZE") {
+            Ok(s) => s.parse().context("SMTP_MAX_MESSAGE_SIZE")?,
+            Err(_) => 25 * 1024 * 1024,
+        };
+        let threshold = match config::var("SMTP_SPOOL_THRESHOLD") {
+            Ok(s) => s.parse().context("SMTP_SPOOL_THRESHOLD")?,
+            Err(_) => 1024 * 1024,
+        };
+        let dir = match config::var("SMTP_SPOOL_DIR") {
+            Ok(d) => PathBuf::from(d),
+            Err(_) => std::env::temp_dir(),
+        };
+        Ok(SpoolConfig {
+            max_size,
+            threshold,
+            dir,
+        })
+    }
+}
+
+impl SpoolConfig {
+    /// Creates the spool dir, once running as the final user.
+    pub fn create_dir(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("create spool dir {}", self.dir.display()))
+    }
+}
+
+/// Buffers an incoming mail body, in memory while it is small and in an
+/// anonymous temp file once it grows past the threshold.
+pub struct Spool {
+    config: Arc<SpoolConfig>,
+    memory: Vec<u8>,
+    file: Option<File>,
+    len: usize,
+    exceeded: bool,
+}
+
+impl Spool {
+    pub fn new(config: SpoolConfig) -> Self {
+        Spool {
+            config: Arc::new(config),
+            memory: Vec::new(),
+            file: None,
+            len: 0,
+            exceeded: false,
+        }
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.config.max_size
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.exceeded {
+            return Ok(());
+        }
+        if self.len + buf.len() > self.config.max_size {
+            // keep reading the rest of DATA but drop it, the mail is rejected at the end
+            self.exceeded = true;
+            self.clear_data();
+            return Ok(());
+        }
+        if self.file.is_none() && self.len + buf.len() > self.config.threshold {
+            let mut file = tempfile::tempfile_in(&self.config.dir)?;
+            file.write_all(&self.memory)?;
+            self.memory = Vec::new();
+            self.file = Some(file);
+        }
+        match &mut self.file {
+            Some(file) => file.write_all(buf)?,
+            None => self.memory.extend_from_slice(buf),
+        }
+        self.len += buf.len();
+        Ok(())
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_spooled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn reader(&mut self) -> io::Result<Box<dyn Read + '_>> {
+        match &mut self.file {
+            Some(file) => {
+                file.seek(SeekFrom::Start(0))?;
+                Ok(Box::new(file.take(self.len as u64)))
+            }
+            None => Ok(Box::new(&self.memory[..])),
+        }
+    }
+
+    pub fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.len);
+        self.reader()?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    pub fn clear(&mut self) {
+        self.clear_data();
+        self.exceeded = false;
+    }
+
+    fn clear_data(&mut self) {
+        self.memory.clear();
+        self.file = None;
+        self.len = 0;
+    }
+}
+
+/// Clones start out empty, the server only clones the idle handler for each new session.
+impl Clone for Spool {
+    fn clone(&self) -> Self {
+        Spool {
+            config: self.config.clone(),
+            memory: Vec::new(),
+            file: None,
+            len: 0,
+            exceeded: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spool(max_size: usize, threshold: usize) -> Spool {
+        Spool::new(SpoolConfig {
+            max_size,
+            threshold,
+            dir: std::env::temp_dir(),
+        })
+    }
+
+    #[test]
+    fn test_spool_to_file() {
+        let mut spool = spool(100, 10);
+        spool.write(b"hello ").unwrap();
+        assert!(!spool.is_spooled());
+        spool.write(b"world!").unwrap();
+        assert!(spool.is_spooled());
+        assert_eq!(spool.read_all().unwrap(), b"hello world!");
+        // reading twice starts from the beginning again
+        assert_eq!(spool.read_all().unwrap(), b"hello world!");
+        spool.clear();
+        assert!(!spool.is_spooled());
+        assert_eq!(spool.len(), 0);
+    }
+
+    #[test]
+    fn test_spool_exceeded() {
+        let mut spool = spool(10, 5);
+        spool.write(b"12345678").unwrap();
+        assert!(!spool.exceeded());
+        spool.write(b"90a").unwrap();
+        assert!(spool.exceeded());
+        spool.write(b"b").unwrap();
+        assert!(spool.exceeded());
+        assert_eq!(spool.len(), 0);
+        spool.clear();
+        assert!(!spool.exceeded());
+    }
+}
@@ -1,8 +1,95 @@
-use anyhow::Result;
-use log::{debug, error};
-use rusqlite::{params, Connection, OptionalExtension};
+use crate::smtp_server::envelope::MailEnvelope;
+use crate::smtp_server::mail::{parse_subject, read_headers};
+use crate::{metrics, telemetry};
+use anyhow::{bail, Result};
+use log::{debug, error, warn};
+use opentelemetry::KeyValue;
+use rusqlite::blob::ZeroBlob;
+use rusqlite::{params, Connection, DatabaseName, OptionalExtension};
+use std::io::{self, Read};
 use std::sync::{Arc, Once};
 
+/// Optional conditions of `list_chat_mails`, text matches are case-insensitive substrings.
+#[derive(Default)]
+pub struct MailFilter {
+    pub from: Option<String>,
+    pub subject: Option<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+}
+
+pub struct MailSummary {
+    pub id: String,
+    pub mail_from: String,
+    /// Stored at ingest, mails of older versions get it from the startup backfill.
+    /// Empty when the mail has no subject, None only for a NULL column.
+    pub subject: Option<String>,
+    pub received_at: i64,
+    pub size: u64,
+}
+
+/// How attachments reach a chat.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RenderMode {
+    /// Uploaded as file messages, linked only when Feishu refuses them.
+    #[default]
+    Full,
+    /// Only download links in the text message.
+    Links,
+}
+
+impl RenderMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RenderMode::Full => "full",
+            RenderMode::Links => "links",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "full" => Some(RenderMode::Full),
+            "links" => Some(RenderMode::Links),
+            _ => None,
+        }
+    }
+}
+
+/// Delivery settings of a chat, edited on the admin page.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChatSettings {
+    /// Mails are still stored, just not forwarded.
+    pub muted: bool,
+    /// Envelope senders that are not forwarded, whole addresses or `@domain`.
+    pub blocked_senders: Vec<String>,
+    pub render_mode: RenderMode,
+    /// Days the chat keeps its mails, None leaves them to the global purge.
+    pub retention_days: Option<u32>,
+}
+
+impl ChatSettings {
+    pub fn blocks(&self, mail_from: &str) -> bool {
+        let mail_from = mail_from.to_lowercase();
+        self.blocked_senders.iter().any(|sender| {
+            let sender = sender.to_lowercase();
+            if sender.starts_with('@') {
+                mail_from.ends_with(&sender)
+            } else {
+                mail_from == sender
+            }
+        })
+    }
+}
+
+pub struct ChatSummary {
+    pub id: String,
+    pub aliases: Vec<String>,
+    pub settings: ChatSettings,
+    /// Mails received since the `since` of `chat_summaries`.
+    pub recent_mails: u64,
+    pub total_mails: u64,
+}
+
 pub struct Store {
     path: Option<String>,
     connection: Connection,
@@ -56,6 +143,14 @@ impl Store {
         Store::new(None, "test".to_string())
     }
 
+    /// Checkpoints the WAL if there is one, called before exiting.
+    pub fn flush(&self) -> Result<()> {
+        self.connection
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        debug!("store flushed");
+        Ok(())
+    }
+
     fn init(&self) {
         self.inited.call_once(|| {
             self.init_raw().unwrap();
@@ -70,6 +165,10 @@ impl Store {
                     )"#,
             (),
         )?;
+        self.add_column("chat", "muted", "BOOLEAN NOT NULL DEFAULT 0")?;
+        self.add_column("chat", "blocked_senders", "TEXT NOT NULL DEFAULT ''")?;
+        self.add_column("chat", "render_mode", "TEXT NOT NULL DEFAULT 'full'")?;
+        self.add_column("chat", "retention_days", "INTEGER")?;
         self.connection.execute(
             r#"CREATE TABLE IF NOT EXISTS mail (
                         id VARCHAR(100) PRIMARY KEY,
@@ -77,9 +176,189 @@ impl Store {
                     )"#,
             (),
         )?;
+        self.add_column("mail", "mail_from", "TEXT")?;
+        self.add_column("mail", "rcpts", "TEXT")?;
+        self.add_column("mail", "helo", "TEXT")?;
+        self.add_column("mail", "client_ip", "VARCHAR(64)")?;
+        self.add_column("mail", "tls", "BOOLEAN")?;
+        self.add_column("mail", "auth", "TEXT")?;
+        self.add_column("mail", "received_at", "INTEGER")?;
+        self.add_column("mail", "links_revoked", "BOOLEAN")?;
+        // extracted when the mail is received, for filtering the API listing
+        self.add_column("mail", "subject", "TEXT")?;
+        self.connection.execute(
+            r#"CREATE TABLE IF NOT EXISTS mail_chat (
+                        chat_id VARCHAR(100) NOT NULL,
+                        mail_id VARCHAR(100) NOT NULL,
+                        PRIMARY KEY (chat_id, mail_id)
+                    )"#,
+            (),
+        )?;
+        self.connection.execute(
+            r#"CREATE TABLE IF NOT EXISTS smtp_credential (
+                        username VARCHAR(100) PRIMARY KEY,
+                        chat_id VARCHAR(100) NOT NULL,
+                        password_hash TEXT NOT NULL
+                    )"#,
+            (),
+        )?;
+        self.connection.execute(
+            r#"CREATE TABLE IF NOT EXISTS setting (
+                        name VARCHAR(100) PRIMARY KEY,
+                        value TEXT NOT NULL
+                    )"#,
+            (),
+        )?;
+        self.connection.execute(
+            r#"CREATE TABLE IF NOT EXISTS api_key (
+                        chat_id VARCHAR(100) PRIMARY KEY,
+                        key_hash VARCHAR(64) NOT NULL UNIQUE,
+                        created_at INTEGER NOT NULL
+                    )"#,
+            (),
+        )?;
+        self.connection.execute(
+            r#"CREATE TABLE IF NOT EXISTS chat_alias (
+                        alias VARCHAR(100) PRIMARY KEY,
+                        chat_id VARCHAR(100) NOT NULL
+                    )"#,
+            (),
+        )?;
+        self.connection.execute(
+            r#"CREATE TABLE IF NOT EXISTS greylist (
+                        triplet VARCHAR(500) PRIMARY KEY,
+                        first_seen INTEGER NOT NULL,
+                        passed_until INTEGER
+                    )"#,
+            (),
+        )?;
+        // backfills go through the regular queries, so every table must exist first
+        self.backfill_mail_chats()?;
+        self.backfill_mail_subjects()?;
+        Ok(())
+    }
+
+    /// Adds a column to tables created by older versions.
+    fn add_column(&self, table: &str, column: &str, ty: &str) -> Result<()> {
+        let count: isize = self.connection.query_row(
+            &format!(
+                "SELECT count(0) FROM pragma_table_info('{}') WHERE name = ?",
+                table
+            ),
+            [column],
+            |row| row.get(0),
+        )?;
+        if count == 0 {
+            debug!("add column {}.{}", table, column);
+            self.connection.execute(
+                &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, ty),
+                (),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Links mails stored by older versions to their chats, once.
+    fn backfill_mail_chats(&self) -> Result<()> {
+        let done = self
+            .connection
+            .query_row(
+                "SELECT 1 FROM setting WHERE name = 'mail_chat_backfilled'",
+                [],
+                |_| Ok(()),
+            )
+            .optional()?;
+        if done.is_some() {
+            return Ok(());
+        }
+        let mut stmt = self
+            .connection
+            .prepare("SELECT id, rcpts FROM mail WHERE rcpts != ''")?;
+        let mails = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+        for (id, rcpts) in &mails {
+            let rcpts: Vec<String> = rcpts.split(',').map(|r| r.to_string()).collect();
+            self.link_mail_to_chats(id, &rcpts)?;
+        }
+        self.connection.execute(
+            "INSERT INTO setting (name, value) VALUES ('mail_chat_backfilled', '1')",
+            (),
+        )?;
+        debug!("linked {} old mails to their chats", mails.len());
+        Ok(())
+    }
+
+    /// Extracts the subjects of mails stored by older versions, once.
+    fn backfill_mail_subjects(&self) -> Result<()> {
+        let done = self
+            .connection
+            .query_row(
+                "SELECT 1 FROM setting WHERE name = 'mail_subject_backfilled'",
+                [],
+                |_| Ok(()),
+            )
+            .optional()?;
+        if done.is_some() {
+            return Ok(());
+        }
+        let mut stmt = self
+            .connection
+            .prepare("SELECT rowid, id FROM mail WHERE subject IS NULL")?;
+        let mails = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(i64, String)>>>()?;
+        for (rowid, id) in &mails {
+            let blob =
+                self.connection
+                    .blob_open(DatabaseName::Main, "mail", "body", *rowid, true)?;
+            let subject = parse_subject(&read_headers(blob)?).unwrap_or_else(|e| {
+                warn!("parse subject of {} error: {}", id, e);
+                String::new()
+            });
+            self.set_mail_subject(id, &subject)?;
+        }
+        self.connection.execute(
+            "INSERT INTO setting (name, value) VALUES ('mail_subject_backfilled', '1')",
+            (),
+        )?;
+        debug!("extracted the subjects of {} old mails", mails.len());
         Ok(())
     }
 
+    fn link_mail_to_chats(&self, id: &str, rcpts: &[String]) -> Result<()> {
+        for rcpt in rcpts {
+            let chat_id = match self.rcpt_chat(rcpt)? {
+                Some(chat_id) => chat_id,
+                // chats the bot left still own their old mails
+                None => match rcpt.split_once('@') {
+                    Some((chat_id, _)) => chat_id.to_string(),
+                    None => continue,
+                },
+            };
+            self.connection.execute(
+                "INSERT OR IGNORE INTO mail_chat (chat_id, mail_id) VALUES (?, ?)",
+                [&chat_id, id],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Makes a write that is rolled back, fails if the database is read-only or locked.
+    pub fn check_writable(&self) -> Result<()> {
+        self.connection.execute_batch(
+            r#"SAVEPOINT check_writable;
+                INSERT INTO chat (id) VALUES ('check_writable');
+                ROLLBACK TO check_writable;
+                RELEASE check_writable;"#,
+        )?;
+        Ok(())
+    }
+
+    pub fn mail_domain(&self) -> &str {
+        &self.mail_domain
+    }
+
     pub fn add_bot_to_chat(&self, chat_id: &str) -> Result<()> {
         let affected = self
             .connection
@@ -93,10 +372,19 @@ impl Store {
             .connection
             .execute("DELETE FROM chat WHERE id = ?", &[chat_id])?;
         debug!("remove bot from chat: {}, affected: {}", chat_id, affected);
+        self.connection
+            .execute("DELETE FROM smtp_credential WHERE chat_id = ?", [chat_id])?;
+        self.connection
+            .execute("DELETE FROM api_key WHERE chat_id = ?", [chat_id])?;
+        self.connection
+            .execute("DELETE FROM chat_alias WHERE chat_id = ?", [chat_id])?;
         Ok(())
     }
 
     pub fn exist_chat(&self, chat_id: &str) -> bool {
+        let _timer = metrics::STORE_DURATION
+            .with_label_values(&["exist_chat"])
+            .start_timer();
         debug!("exist chat: {}", chat_id);
         let count: isize = match self.connection.query_row(
             "SELECT count(0) FROM chat WHERE id = ?",
@@ -113,6 +401,138 @@ impl Store {
         count > 0
     }
 
+    /// The chat a recipient address goes to, by chat id or alias.
+    pub fn rcpt_chat(&self, rcpt: &str) -> Result<Option<String>> {
+        let local = rcpt.split('@').next().unwrap_or_default();
+        if self.exist_chat(local) {
+            return Ok(Some(local.to_string()));
+        }
+        let chat_id = self
+            .connection
+            .query_row(
+                "SELECT chat_id FROM chat_alias WHERE alias = ?",
+                [local.to_lowercase()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(chat_id)
+    }
+
+    pub fn chat_aliases(&self, chat_id: &str) -> Result<Vec<String>> {
+        let mut stmt = self
+            .connection
+            .prepare("SELECT alias FROM chat_alias WHERE chat_id = ? ORDER BY alias")?;
+        let aliases = stmt
+            .query_map([chat_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(aliases)
+    }
+
+    /// Saves the settings and aliases of the admin page together, nothing is
+    /// written if either fails. False if the bot is not in the chat.
+    pub fn update_chat(
+        &self,
+        chat_id: &str,
+        settings: &ChatSettings,
+        aliases: &[String],
+    ) -> Result<bool> {
+        let tx = self.connection.unchecked_transaction()?;
+        if !self.set_chat_settings(chat_id, settings)? {
+            return Ok(false);
+        }
+        self.replace_chat_aliases(chat_id, aliases)?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    /// Replaces the aliases of the chat, fails if one belongs to another chat.
+    fn replace_chat_aliases(&self, chat_id: &str, aliases: &[String]) -> Result<()> {
+        self.connection
+            .execute("DELETE FROM chat_alias WHERE chat_id = ?", [chat_id])?;
+        for alias in aliases {
+            let alias = alias.to_lowercase();
+            if self.exist_chat(&alias) {
+                bail!("`{}` is the id of another chat", alias);
+            }
+            let inserted = self.connection.execute(
+                "INSERT OR IGNORE INTO chat_alias (alias, chat_id) VALUES (?, ?)",
+                [&alias, chat_id],
+            )?;
+            if inserted == 0 {
+                bail!("`{}` is already an alias of another chat", alias);
+            }
+        }
+        debug!("set aliases of chat {}: {:?}", chat_id, aliases);
+        Ok(())
+    }
+
+    pub fn list_chats(&self) -> Result<Vec<String>> {
+        let mut stmt = self.connection.prepare("SELECT id FROM chat ORDER BY id")?;
+        let chats = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(chats)
+    }
+
+    /// None if the bot is not in the chat.
+    pub fn chat_settings(&self, chat_id: &str) -> Result<Option<ChatSettings>> {
+        let settings = self
+            .connection
+            .query_row(
+                "SELECT muted, blocked_senders, render_mode, retention_days FROM chat WHERE id = ?",
+                [chat_id],
+                chat_settings_from_row,
+            )
+            .optional()?;
+        Ok(settings)
+    }
+
+    /// Returns false if the bot is not in the chat.
+    pub fn set_chat_settings(&self, chat_id: &str, settings: &ChatSettings) -> Result<bool> {
+        let affected = self.connection.execute(
+            r#"UPDATE chat SET muted = ?, blocked_senders = ?, render_mode = ?, retention_days = ?
+                WHERE id = ?"#,
+            params![
+                settings.muted,
+                settings.blocked_senders.join("\n"),
+                settings.render_mode.as_str(),
+                settings.retention_days,
+                chat_id
+            ],
+        )?;
+        debug!("set settings of chat {}: {:?}", chat_id, settings);
+        Ok(affected > 0)
+    }
+
+    /// Every chat with its settings and mail counts.
+    pub fn chat_summaries(&self, since: i64) -> Result<Vec<ChatSummary>> {
+        let mut stmt = self.connection.prepare(
+            r#"SELECT chat.muted, chat.blocked_senders, chat.render_mode, chat.retention_days,
+                    chat.id, count(mail.id), coalesce(sum(mail.received_at >= ?), 0),
+                    (SELECT group_concat(alias, ' ') FROM
+                        (SELECT alias FROM chat_alias WHERE chat_id = chat.id ORDER BY alias))
+                FROM chat
+                LEFT JOIN mail_chat ON mail_chat.chat_id = chat.id
+                LEFT JOIN mail ON mail.id = mail_chat.mail_id
+                GROUP BY chat.id ORDER BY chat.id"#,
+        )?;
+        let chats = stmt
+            .query_map([since], |row| {
+                Ok(ChatSummary {
+                    settings: chat_settings_from_row(row)?,
+                    id: row.get(4)?,
+                    total_mails: row.get(5)?,
+                    recent_mails: row.get(6)?,
+                    aliases: row
+                        .get::<_, Option<String>>(7)?
+                        .map(|aliases| aliases.split(' ').map(String::from).collect())
+                        .unwrap_or_default(),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(chats)
+    }
+
     pub fn mail_for_chat(&self, chat_id: &str) -> Result<String> {
         debug!("mail for chat: {}", chat_id);
         if !self.exist_chat(chat_id) {
@@ -121,16 +541,71 @@ impl Store {
         Ok(format!("{}@{}", chat_id, &self.mail_domain))
     }
 
-    pub fn save_mail(&self, id: &str, body: &Vec<u8>) -> Result<()> {
+    #[cfg(test)]
+    pub fn save_mail(&self, id: &str, body: &[u8]) -> Result<()> {
+        self.save_mail_from_reader(id, &MailEnvelope::default(), "", body.len(), &mut &body[..])
+    }
+
+    /// Streams the body into the row so large spooled mails never have to be held in memory.
+    pub fn save_mail_from_reader(
+        &self,
+        id: &str,
+        envelope: &MailEnvelope,
+        subject: &str,
+        len: usize,
+        body: &mut dyn Read,
+    ) -> Result<()> {
+        let _span = telemetry::span(
+            "store.save_mail",
+            vec![
+                KeyValue::new("mail.id", id.to_string()),
+                KeyValue::new("mail.size", len as i64),
+            ],
+        );
+        let _timer = metrics::STORE_DURATION
+            .with_label_values(&["save_mail"])
+            .start_timer();
+        // a half written body must not stay behind as a zero filled mail
+        let tx = self.connection.unchecked_transaction()?;
         let affected = self.connection.execute(
-            "INSERT OR IGNORE INTO mail (id, body) VALUES (?, ?)",
-            params![id, body],
+            r#"INSERT OR IGNORE INTO mail
+                (id, body, mail_from, rcpts, helo, client_ip, tls, auth, received_at, subject)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+            params![
+                id,
+                ZeroBlob(i32::try_from(len)?),
+                &envelope.mail_from,
+                envelope.rcpts.join(","),
+                &envelope.helo,
+                envelope.client_ip.map(|ip| ip.to_string()),
+                envelope.tls,
+                &envelope.auth,
+                envelope.received_at,
+                subject,
+            ],
         )?;
         debug!("save mail: {}, inserted: {}", id, affected);
+        if affected == 0 {
+            return Ok(());
+        }
+        let rowid = self.connection.last_insert_rowid();
+        let mut blob =
+            self.connection
+                .blob_open(DatabaseName::Main, "mail", "body", rowid, false)?;
+        let copied = io::copy(body, &mut blob)?;
+        drop(blob);
+        if copied != len as u64 {
+            bail!("mail {} has {} bytes, expected {}", id, copied, len);
+        }
+        self.link_mail_to_chats(id, &envelope.rcpts)?;
+        tx.commit()?;
         Ok(())
     }
 
     pub fn get_mail(&self, id: &str) -> Result<Option<Vec<u8>>> {
+        let _timer = metrics::STORE_DURATION
+            .with_label_values(&["get_mail"])
+            .start_timer();
         debug!("get mail: {}", id);
         let body: Option<Vec<u8>> = self
             .connection
@@ -140,11 +615,437 @@ impl Store {
             .optional()?;
         Ok(body)
     }
+
+    /// Returns false if there is no such mail.
+    pub fn revoke_mail_links(&self, id: &str) -> Result<bool> {
+        let affected = self
+            .connection
+            .execute("UPDATE mail SET links_revoked = 1 WHERE id = ?", [id])?;
+        Ok(affected > 0)
+    }
+
+    pub fn mail_links_revoked(&self, id: &str) -> Result<bool> {
+        let revoked: Option<Option<bool>> = self
+            .connection
+            .query_row("SELECT links_revoked FROM mail WHERE id = ?", [id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(revoked.flatten().unwrap_or(false))
+    }
+
+    /// Returns the stored link signing key, storing `new_key` first if there is none.
+    pub fn link_key(&self, new_key: &str) -> Result<String> {
+        self.setting_or_insert("link_key", new_key)
+    }
+
+    /// Returns the stored web session signing key, storing `new_key` first if there is none.
+    pub fn session_key(&self, new_key: &str) -> Result<String> {
+        self.setting_or_insert("session_key", new_key)
+    }
+
+    fn setting_or_insert(&self, name: &str, value: &str) -> Result<String> {
+        self.connection.execute(
+            "INSERT OR IGNORE INTO setting (name, value) VALUES (?, ?)",
+            [name, value],
+        )?;
+        let value = self.connection.query_row(
+            "SELECT value FROM setting WHERE name = ?",
+            [name],
+            |row| row.get(0),
+        )?;
+        Ok(value)
+    }
+
+    /// Deletes mails received before `before`. Mails stored by older versions have
+    /// no receive time and are kept.
+    pub fn purge_mails(&self, before: i64) -> Result<usize> {
+        let affected = self.connection.execute(
+            "DELETE FROM mail WHERE received_at > 0 AND received_at < ?",
+            [before],
+        )?;
+        debug!("purge mails before {}, deleted: {}", before, affected);
+        self.connection.execute(
+            "DELETE FROM mail_chat WHERE mail_id NOT IN (SELECT id FROM mail)",
+            (),
+        )?;
+        Ok(affected)
+    }
+
+    pub fn get_mail_envelope(&self, id: &str) -> Result<Option<MailEnvelope>> {
+        let envelope = self
+            .connection
+            .query_row(
+                r#"SELECT mail_from, rcpts, helo, client_ip, tls, auth, received_at
+                    FROM mail WHERE id = ?"#,
+                [id],
+                |row| {
+                    let rcpts: Option<String> = row.get(1)?;
+                    let client_ip: Option<String> = row.get(3)?;
+                    Ok(MailEnvelope {
+                        mail_from: row.get::<_, Option<String>>(0)?.unwrap_or_default(),
+                        rcpts: rcpts
+                            .unwrap_or_default()
+                            .split(',')
+                            .filter(|r| !r.is_empty())
+                            .map(|r| r.to_string())
+                            .collect(),
+                        helo: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                        client_ip: client_ip.and_then(|ip| ip.parse().ok()),
+                        tls: row.get::<_, Option<bool>>(4)?.unwrap_or_default(),
+                        auth: row.get(5)?,
+                        received_at: row.get::<_, Option<i64>>(6)?.unwrap_or_default(),
+                    })
+                },
+            )
+            .optional()?;
+        Ok(envelope)
+    }
+
+    pub fn set_smtp_credential(
+        &self,
+        username: &str,
+        chat_id: &str,
+        password_hash: &str,
+    ) -> Result<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO smtp_credential (username, chat_id, password_hash) VALUES (?, ?, ?)",
+            params![username, chat_id, password_hash],
+        )?;
+        debug!("set smtp credential: {}, chat: {}", username, chat_id);
+        Ok(())
+    }
+
+    pub fn get_smtp_credential(&self, username: &str) -> Result<Option<(String, String)>> {
+        let credential = self
+            .connection
+            .query_row(
+                "SELECT chat_id, password_hash FROM smtp_credential WHERE username = ?",
+                [username],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(credential)
+    }
+
+    /// Replaces the chat's API key, only the SHA-256 of the key is kept.
+    pub fn set_api_key(&self, chat_id: &str, key_hash: &str, created_at: i64) -> Result<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO api_key (chat_id, key_hash, created_at) VALUES (?, ?, ?)",
+            params![chat_id, key_hash, created_at],
+        )?;
+        debug!("set api key for chat: {}", chat_id);
+        Ok(())
+    }
+
+    pub fn chat_for_api_key(&self, key_hash: &str) -> Result<Option<String>> {
+        let chat_id = self
+            .connection
+            .query_row(
+                "SELECT chat_id FROM api_key WHERE key_hash = ?",
+                [key_hash],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(chat_id)
+    }
+
+    /// Mails of the chat matching `filter`, newest first, with the total number of matches.
+    pub fn list_chat_mails(
+        &self,
+        chat_id: &str,
+        filter: &MailFilter,
+        limit: u32,
+        offset: u32,
+    ) -> Result<(u64, Vec<MailSummary>)> {
+        let _timer = metrics::STORE_DURATION
+            .with_label_values(&["list_chat_mails"])
+            .start_timer();
+        let condition = r#"FROM mail JOIN mail_chat ON mail_chat.mail_id = mail.id
+            WHERE mail_chat.chat_id = :chat_id
+                AND (:from IS NULL OR instr(lower(mail.mail_from), lower(:from)) > 0)
+                AND (:subject IS NULL OR instr(lower(mail.subject), lower(:subject)) > 0)
+                AND (:since IS NULL OR mail.received_at >= :since)
+                AND (:until IS NULL OR mail.received_at < :until)"#;
+        let params = rusqlite::named_params! {
+            ":chat_id": chat_id,
+            ":from": filter.from,
+            ":subject": filter.subject,
+            ":since": filter.since,
+            ":until": filter.until,
+        };
+        let total: u64 = self.connection.query_row(
+            &format!("SELECT count(0) {}", condition),
+            params,
+            |row| row.get(0),
+        )?;
+        let mut stmt = self.connection.prepare(&format!(
+            r#"SELECT mail.id, mail.mail_from, mail.subject, mail.received_at, length(mail.body)
+                {} ORDER BY mail.received_at DESC, mail.id LIMIT {} OFFSET {}"#,
+            condition, limit, offset
+        ))?;
+        let mails = stmt
+            .query_map(params, |row| {
+                Ok(MailSummary {
+                    id: row.get(0)?,
+                    mail_from: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                    subject: row.get(2)?,
+                    received_at: row.get::<_, Option<i64>>(3)?.unwrap_or_default(),
+                    size: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok((total, mails))
+    }
+
+    fn set_mail_subject(&self, id: &str, subject: &str) -> Result<()> {
+        self.connection
+            .execute("UPDATE mail SET subject = ? WHERE id = ?", [subject, id])?;
+        Ok(())
+    }
+
+    /// The chats a mail was delivered to.
+    pub fn mail_chats(&self, id: &str) -> Result<Vec<String>> {
+        let mut stmt = self
+            .connection
+            .prepare("SELECT chat_id FROM mail_chat WHERE mail_id = ? ORDER BY chat_id")?;
+        let chats = stmt
+            .query_map([id], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(chats)
+    }
+
+    pub fn chat_has_mail(&self, chat_id: &str, id: &str) -> Result<bool> {
+        let found = self
+            .connection
+            .query_row(
+                "SELECT 1 FROM mail_chat WHERE chat_id = ? AND mail_id = ?",
+                [chat_id, id],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    /// Removes the mail from the chat, the mail itself goes once no chat has it.
+    /// Returns false if the chat didn't have it.
+    pub fn delete_chat_mail(&self, chat_id: &str, id: &str) -> Result<bool> {
+        let affected = self.connection.execute(
+            "DELETE FROM mail_chat WHERE chat_id = ? AND mail_id = ?",
+            [chat_id, id],
+        )?;
+        if affected == 0 {
+            return Ok(false);
+        }
+        let deleted = self.connection.execute(
+            "DELETE FROM mail WHERE id = ? AND NOT EXISTS (SELECT 1 FROM mail_chat WHERE mail_id = ?)",
+            [id, id],
+        )?;
+        debug!(
+            "delete mail {} of chat {}, mail deleted: {}",
+            id, chat_id, deleted
+        );
+        Ok(true)
+    }
+
+    /// Removes mails from chats with a retention older than it, mails no chat
+    /// has any more are deleted. Returns how many were removed from chats.
+    pub fn purge_chat_mails(&self, now: i64) -> Result<usize> {
+        let mut stmt = self.connection.prepare(
+            r#"SELECT mail_chat.chat_id, mail_chat.mail_id FROM mail_chat
+                JOIN chat ON chat.id = mail_chat.chat_id
+                JOIN mail ON mail.id = mail_chat.mail_id
+                WHERE chat.retention_days IS NOT NULL AND mail.received_at > 0
+                    AND mail.received_at < ? - chat.retention_days * 86400"#,
+        )?;
+        let expired = stmt
+            .query_map([now], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+        for (chat_id, id) in &expired {
+            self.delete_chat_mail(chat_id, id)?;
+        }
+        Ok(expired.len())
+    }
+
+    pub fn get_greylist(&self, triplet: &str) -> Result<Option<(i64, Option<i64>)>> {
+        let _timer = metrics::STORE_DURATION
+            .with_label_values(&["get_greylist"])
+            .start_timer();
+        let entry = self
+            .connection
+            .query_row(
+                "SELECT first_seen, passed_until FROM greylist WHERE triplet = ?",
+                [triplet],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(entry)
+    }
+
+    pub fn add_greylist(&self, triplet: &str, first_seen: i64) -> Result<()> {
+        let _timer = metrics::STORE_DURATION
+            .with_label_values(&["add_greylist"])
+            .start_timer();
+        self.connection.execute(
+            "INSERT OR REPLACE INTO greylist (triplet, first_seen, passed_until) VALUES (?, ?, NULL)",
+            params![triplet, first_seen],
+        )?;
+        debug!("add greylist: {}", triplet);
+        Ok(())
+    }
+
+    pub fn pass_greylist(&self, triplet: &str, passed_until: i64) -> Result<()> {
+        let _timer = metrics::STORE_DURATION
+            .with_label_values(&["pass_greylist"])
+            .start_timer();
+        self.connection.execute(
+            "UPDATE greylist SET passed_until = ? WHERE triplet = ?",
+            params![passed_until, triplet],
+        )?;
+        Ok(())
+    }
+
+    pub fn purge_greylist(&self, now: i64, ttl: i64) -> Result<usize> {
+        let affected = self.connection.execute(
+            "DELETE FROM greylist WHERE first_seen < ?1 AND coalesce(passed_until, 0) < ?2",
+            params![now - ttl, now],
+        )?;
+        debug!("purge greylist, deleted: {}", affected);
+        Ok(affected)
+    }
+}
+
+fn chat_settings_from_row(row: &rusqlite::Row) -> rusqlite::Result<ChatSettings> {
+    let blocked_senders: String = row.get(1)?;
+    let render_mode: String = row.get(2)?;
+    Ok(ChatSettings {
+        muted: row.get(0)?,
+        blocked_senders: blocked_senders
+            .lines()
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect(),
+        render_mode: RenderMode::parse(&render_mode).unwrap_or_default(),
+        retention_days: row.get(3)?,
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::store::Store;
+    use crate::smtp_server::envelope::MailEnvelope;
+    use crate::store::{ChatSettings, MailFilter, RenderMode, Store};
+
+    #[test]
+    fn test_upgrade_old_schema() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_string_lossy().into_owned();
+        // mails stored before chat aliases and the mail_chat links existed
+        let old = rusqlite::Connection::open(&path).unwrap();
+        old.execute_batch(
+            "CREATE TABLE chat (id VARCHAR(100) PRIMARY KEY);
+            CREATE TABLE mail (id VARCHAR(100) PRIMARY KEY, body BLOB, rcpts TEXT);
+            CREATE TABLE setting (name VARCHAR(100) PRIMARY KEY, value TEXT NOT NULL);
+            INSERT INTO chat (id) VALUES ('c1');
+            INSERT INTO mail (id, body, rcpts) VALUES ('m1', X'00', 'c1@test');
+            INSERT INTO mail (id, body, rcpts) VALUES ('m2', X'00', 'gone@test');",
+        )
+        .unwrap();
+        drop(old);
+        let store = Store::new(Some(path), "test".to_string()).unwrap();
+        assert_eq!(store.mail_chats("m1").unwrap(), vec!["c1"]);
+        assert!(store.chat_has_mail("c1", "m1").unwrap());
+        // a chat that is gone is looked up as an alias
+        assert_eq!(store.mail_chats("m2").unwrap(), vec!["gone"]);
+    }
+
+    #[test]
+    fn test_check_writable() {
+        let store = Store::in_memory().unwrap();
+        store.check_writable().unwrap();
+        assert!(!store.exist_chat("check_writable"));
+    }
+
+    #[test]
+    fn test_revoke_mail_links() {
+        let store = Store::in_memory().unwrap();
+        store.save_mail("id1", b"body").unwrap();
+        assert!(!store.mail_links_revoked("id1").unwrap());
+        assert!(store.revoke_mail_links("id1").unwrap());
+        assert!(store.mail_links_revoked("id1").unwrap());
+        assert!(!store.revoke_mail_links("missing").unwrap());
+        assert_eq!(store.link_key("a").unwrap(), "a");
+        assert_eq!(store.link_key("b").unwrap(), "a");
+        assert_eq!(store.session_key("c").unwrap(), "c");
+    }
+
+    #[test]
+    fn test_chat_mails() {
+        let store = Store::in_memory().unwrap();
+        for (id, from, subject, received_at, rcpts) in [
+            (
+                "m1",
+                "alice@a.com",
+                "Weekly Report",
+                100,
+                vec!["c1@test", "c2@test"],
+            ),
+            ("m2", "bob@b.com", "", 200, vec!["c1@test"]),
+            ("m3", "Alice@a.com", "", 300, vec!["c2@test"]),
+        ] {
+            let envelope = MailEnvelope {
+                mail_from: from.to_string(),
+                rcpts: rcpts.into_iter().map(String::from).collect(),
+                received_at,
+                ..Default::default()
+            };
+            store
+                .save_mail_from_reader(id, &envelope, subject, 4, &mut &b"body"[..])
+                .unwrap();
+        }
+        assert_eq!(store.mail_chats("m1").unwrap(), vec!["c1", "c2"]);
+
+        let (total, mails) = store
+            .list_chat_mails("c1", &MailFilter::default(), 1, 0)
+            .unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(mails[0].id, "m2");
+        assert_eq!(mails[0].size, 4);
+        let filter = MailFilter {
+            subject: Some("report".to_string()),
+            ..Default::default()
+        };
+        let (_, mails) = store.list_chat_mails("c1", &filter, 10, 0).unwrap();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].subject.as_deref(), Some("Weekly Report"));
+        let filter = MailFilter {
+            from: Some("ALICE".to_string()),
+            until: Some(300),
+            ..Default::default()
+        };
+        let (total, _) = store.list_chat_mails("c2", &filter, 10, 0).unwrap();
+        assert_eq!(total, 1);
+
+        // m1 stays for c2
+        assert!(store.delete_chat_mail("c1", "m1").unwrap());
+        assert!(!store.chat_has_mail("c1", "m1").unwrap());
+        assert!(store.chat_has_mail("c2", "m1").unwrap());
+        assert!(store.get_mail("m1").unwrap().is_some());
+        assert!(!store.delete_chat_mail("c1", "m3").unwrap());
+        assert!(store.delete_chat_mail("c2", "m1").unwrap());
+        assert!(store.get_mail("m1").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_api_key() {
+        let store = Store::in_memory().unwrap();
+        store.add_bot_to_chat("c1").unwrap();
+        store.set_api_key("c1", "h1", 0).unwrap();
+        assert_eq!(store.chat_for_api_key("h1").unwrap().as_deref(), Some("c1"));
+        store.set_api_key("c1", "h2", 0).unwrap();
+        assert_eq!(store.chat_for_api_key("h1").unwrap(), None);
+        store.remove_bot_from_chat("c1").unwrap();
+        assert_eq!(store.chat_for_api_key("h2").unwrap(), None);
+    }
 
     #[test]
     fn test_add_or_remove_bot() {
@@ -164,4 +1065,148 @@ mod tests {
         store.save_mail(mail_id, &body).unwrap();
         assert_eq!(store.get_mail(mail_id).unwrap().unwrap(), body);
     }
+
+    #[test]
+    fn test_save_mail_rolls_back() {
+        struct Broken;
+        impl std::io::Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("spool gone"))
+            }
+        }
+        let store = Store::in_memory().unwrap();
+        store.add_bot_to_chat("c1").unwrap();
+        let envelope = MailEnvelope {
+            rcpts: vec!["c1@test".to_string()],
+            ..Default::default()
+        };
+        let mut body = std::io::Read::chain(&b"half"[..], Broken);
+        assert!(store
+            .save_mail_from_reader("m1", &envelope, "", 8, &mut body)
+            .is_err());
+        assert!(store
+            .save_mail_from_reader("m2", &envelope, "", 8, &mut &b"short"[..])
+            .is_err());
+        for id in ["m1", "m2"] {
+            assert!(store.get_mail(id).unwrap().is_none());
+            assert!(!store.chat_has_mail("c1", id).unwrap());
+        }
+        store
+            .save_mail_from_reader("m1", &envelope, "", 4, &mut &b"body"[..])
+            .unwrap();
+        assert_eq!(store.get_mail("m1").unwrap().unwrap(), b"body");
+    }
+
+    #[test]
+    fn test_save_mail_envelope() {
+        let store = Store::in_memory().unwrap();
+        let envelope = MailEnvelope {
+            mail_from: "a@b.com".to_string(),
+            rcpts: vec!["c1@test".to_string(), "c2@test".to_string()],
+            helo: "mx.b.com".to_string(),
+            client_ip: Some("1.2.3.4".parse().unwrap()),
+            tls: true,
+            auth: Some("c1".to_string()),
+            received_at: 1000,
+        };
+        let body = b"Subject: hi\r\n\r\nhello".to_vec();
+        store
+            .save_mail_from_reader("mail_id", &envelope, "", body.len(), &mut &body[..])
+            .unwrap();
+        assert_eq!(store.get_mail("mail_id").unwrap().unwrap(), body);
+        assert_eq!(
+            store.get_mail_envelope("mail_id").unwrap().unwrap(),
+            envelope
+        );
+    }
+
+    #[test]
+    fn test_chat_settings() {
+        let store = Store::in_memory().unwrap();
+        store.add_bot_to_chat("c1").unwrap();
+        store.add_bot_to_chat("c2").unwrap();
+        assert_eq!(
+            store.chat_settings("c1").unwrap(),
+            Some(ChatSettings::default())
+        );
+        assert_eq!(store.chat_settings("missing").unwrap(), None);
+        let settings = ChatSettings {
+            muted: true,
+            blocked_senders: vec!["spam@a.com".to_string(), "@b.com".to_string()],
+            render_mode: RenderMode::Links,
+            retention_days: Some(1),
+        };
+        assert!(store.set_chat_settings("c1", &settings).unwrap());
+        assert!(!store.set_chat_settings("missing", &settings).unwrap());
+        assert_eq!(store.chat_settings("c1").unwrap(), Some(settings.clone()));
+        assert!(settings.blocks("SPAM@a.com"));
+        assert!(settings.blocks("x@b.com"));
+        assert!(!settings.blocks("x@bb.com.cn"));
+
+        for (id, received_at) in [("old", 1000), ("new", 100_000)] {
+            let envelope = MailEnvelope {
+                rcpts: vec!["c1@test".to_string(), "c2@test".to_string()],
+                received_at,
+                ..Default::default()
+            };
+            store
+                .save_mail_from_reader(id, &envelope, "", 4, &mut &b"body"[..])
+                .unwrap();
+        }
+        let chats = store.chat_summaries(50_000).unwrap();
+        assert_eq!(chats.len(), 2);
+        assert_eq!((chats[0].total_mails, chats[0].recent_mails), (2, 1));
+        assert!(chats[0].aliases.is_empty());
+
+        // only c1 keeps mails for a day, c2 still has the old one
+        assert_eq!(store.purge_chat_mails(100_000).unwrap(), 1);
+        assert!(!store.chat_has_mail("c1", "old").unwrap());
+        assert!(store.chat_has_mail("c2", "old").unwrap());
+        assert!(store.chat_has_mail("c1", "new").unwrap());
+    }
+
+    #[test]
+    fn test_chat_aliases() {
+        let store = Store::in_memory().unwrap();
+        store.add_bot_to_chat("c1").unwrap();
+        store.add_bot_to_chat("c2").unwrap();
+        let settings = store.chat_settings("c2").unwrap().unwrap();
+        let aliases = vec!["ops".to_string(), "Alerts".to_string()];
+        assert!(store.update_chat("c1", &settings, &aliases).unwrap());
+        assert!(!store.update_chat("c3", &settings, &aliases).unwrap());
+        assert_eq!(store.chat_aliases("c1").unwrap(), vec!["alerts", "ops"]);
+        assert_eq!(store.rcpt_chat("c1@test").unwrap().as_deref(), Some("c1"));
+        assert_eq!(store.rcpt_chat("OPS@test").unwrap().as_deref(), Some("c1"));
+        assert_eq!(store.rcpt_chat("nobody@test").unwrap(), None);
+        assert_eq!(
+            store.chat_summaries(0).unwrap()[0].aliases,
+            vec!["alerts", "ops"]
+        );
+
+        // taken aliases and chat ids are refused, nothing of the update is kept
+        let muted = ChatSettings {
+            muted: true,
+            ..settings
+        };
+        assert!(store
+            .update_chat("c2", &muted, &["ops".to_string()])
+            .is_err());
+        assert!(store
+            .update_chat("c2", &muted, &["c1".to_string()])
+            .is_err());
+        assert!(store.chat_aliases("c2").unwrap().is_empty());
+        assert!(!store.chat_settings("c2").unwrap().unwrap().muted);
+
+        let envelope = MailEnvelope {
+            rcpts: vec!["ops@test".to_string()],
+            ..Default::default()
+        };
+        store
+            .save_mail_from_reader("m1", &envelope, "", 4, &mut &b"body"[..])
+            .unwrap();
+        assert!(store.chat_has_mail("c1", "m1").unwrap());
+
+        store.remove_bot_from_chat("c1").unwrap();
+        assert_eq!(store.rcpt_chat("ops@test").unwrap(), None);
+    }
 }
@@ -0,0 +1,88 @@
+use crate::config;
+use anyhow::Result;
+use log::info;
+use opentelemetry::trace::{Status, TraceContextExt, Tracer};
+use opentelemetry::{global, Context, ContextGuard, KeyValue};
+use opentelemetry_otlp::{Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::fmt::Display;
+
+/// Exports spans over OTLP/HTTP when `OTEL_EXPORTER_OTLP_ENDPOINT` is set, e.g.
+/// `http://localhost:4318`. Without a provider every span is a no-op.
+pub fn init() -> Result<Option<SdkTracerProvider>> {
+    let endpoint = match config::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(endpoint) if !endpoint.is_empty() => endpoint,
+        _ => return Ok(None),
+    };
+    let service_name =
+        config::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| env!("CARGO_PKG_NAME").to_string());
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_protocol(Protocol::HttpBinary)
+        .with_endpoint(traces_url(&endpoint))
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service_name).build())
+        .build();
+    global::set_tracer_provider(provider.clone());
+    info!("exporting traces to {}", endpoint);
+    Ok(Some(provider))
+}
+
+/// The env var is the collector base url, the exporter wants the full path.
+fn traces_url(endpoint: &str) -> String {
+    let endpoint = endpoint.trim_end_matches('/');
+    if endpoint.ends_with("/v1/traces") {
+        endpoint.to_string()
+    } else {
+        format!("{}/v1/traces", endpoint)
+    }
+}
+
+/// Starts a span as child of the current one, it ends when the guard is dropped.
+pub fn span(name: &'static str, attributes: Vec<KeyValue>) -> ContextGuard {
+    let tracer = global::tracer("mailhook");
+    let span = tracer
+        .span_builder(name)
+        .with_attributes(attributes)
+        .start(&tracer);
+    Context::current_with_span(span).attach()
+}
+
+pub fn set_attribute(attribute: KeyValue) {
+    Context::map_current(|cx| cx.span().set_attribute(attribute));
+}
+
+pub fn set_error(e: impl Display) {
+    let description = e.to_string();
+    Context::map_current(|cx| cx.span().set_status(Status::error(description)));
+}
+
+/// Trace id of the current span, for log lines.
+pub fn trace_id() -> Option<String> {
+    Context::map_current(|cx| {
+        let span_context = cx.span().span_context().clone();
+        span_context
+            .is_valid()
+            .then(|| span_context.trace_id().to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traces_url() {
+        assert_eq!(
+            traces_url("http://localhost:4318"),
+            "http://localhost:4318/v1/traces"
+        );
+        assert_eq!(
+            traces_url("http://localhost:4318/v1/traces/"),
+            "http://localhost:4318/v1/traces"
+        );
+    }
+}